@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+/// Keywords that look like identifiers, but cannot be used as a type.
+const KEYWORDS: &[&str] = &[
+    "_", "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "macro",
+    "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "self", "static", "struct", "super",
+    "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Where a question is in the macro's body, which decides what its answer can be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Position {
+    /// Where a type goes, like after `:` or `->`.
+    Type,
+    /// Where items go, like at the top of the body.
+    Items,
+}
+
+/// Check that a friend's answer can be put in place of the magic type: a type, like `u32` or `HashMap<K, Vec<V>>`,
+/// for a question in a type's position, or whole items, like an impl block, for a question where items go.
+/// Without a position, like for a `default` that any question can be answered with, either is fine.
+///
+/// The error says what is wrong with the answer, so that it can be shown to the friend.
+pub(crate) fn check_replacement(answer: &str, position: Option<Position>) -> Result<(), String> {
+    if answer.trim().is_empty() {
+        return Err("the answer is empty".to_string());
+    }
+    // Some of these, like `_`, parse as a type, but cannot be used as one.
+    if KEYWORDS.contains(&answer) {
+        return Err(format!("`{answer}` is a keyword, not a type"));
+    }
+    let is_type = syn::parse_str::<syn::Type>(answer).is_ok();
+    let items = syn::parse_str::<syn::File>(answer).ok();
+    // Only comments parse as a file too, but there is nothing in it to replace the question with.
+    if !is_type && items.as_ref().is_some_and(|file| file.items.is_empty() && file.attrs.is_empty()) {
+        return Err(format!("`{answer}` has no code in it"));
+    }
+    // An inner attribute, like `#![allow(unused)]`, can only go at the top of a file or module.
+    if items.as_ref().is_some_and(|file| !file.attrs.is_empty()) {
+        return Err(format!("`{answer}` has an inner attribute, which cannot go in the middle of the code"));
+    }
+    let are_items = items.is_some_and(|file| !file.items.is_empty());
+    match position {
+        Some(Position::Type) if !is_type => {
+            Err(format!("`{answer}` is not a type, like `Vec<u32>`, which is what the question is in place of"))
+        }
+        Some(Position::Items) if !are_items => {
+            Err(format!("`{answer}` is not whole items, like an impl block, which is what the question is in place of"))
+        }
+        None if !is_type && !are_items => Err(format!(
+            "`{answer}` is not Rust code that can replace the question: it must be a type, like `Vec<u32>`, or whole items"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Get the code out of an answer that has it in a fenced code block, like "```rust\nu32\n```".
+/// An answer without one is all code.
+pub(crate) fn extract_code(answer: &str) -> &str {
+    let Some((_, rest)) = answer.split_once("```") else {
+        return answer;
+    };
+    // The info string, like `rust`, is on the rest of the opening line.
+    let code = match rest.split_once('\n') {
+        Some((info, code)) if info.trim().chars().all(|c| c.is_alphanumeric()) => code,
+        _ => rest,
+    };
+    code.split_once("```").map_or(code, |(code, _)| code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_without_code_block_is_all_code() {
+        assert_eq!(extract_code("Vec<u32>"), "Vec<u32>");
+    }
+
+    #[test]
+    fn code_block_is_extracted() {
+        assert_eq!(extract_code("```rust\nu32\n```").trim(), "u32");
+        assert_eq!(extract_code("Try this:\n```\nOption<u8>\n```\nshould work").trim(), "Option<u8>");
+        assert_eq!(extract_code("```u16```").trim(), "u16");
+    }
+
+    #[test]
+    fn unclosed_code_block_runs_to_the_end() {
+        assert_eq!(extract_code("```rust\nString").trim(), "String");
+    }
+
+    #[test]
+    fn answer_must_fit_the_position() {
+        assert!(check_replacement("HashMap<K, Vec<V>>", Some(Position::Type)).is_ok());
+        assert!(check_replacement("struct Foo;", Some(Position::Type)).is_err());
+        assert!(check_replacement("impl Foo {}", Some(Position::Items)).is_ok());
+        assert!(check_replacement("u32", Some(Position::Items)).is_err());
+    }
+
+    #[test]
+    fn answer_without_code_is_rejected() {
+        for answer in ["", "// no idea", "/* ? */", "#![allow(unused)]", "_"] {
+            assert!(check_replacement(answer, None).is_err(), "{answer:?} was accepted");
+        }
+    }
+}
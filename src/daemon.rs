@@ -0,0 +1,164 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AskAFriendError;
+use crate::telegram::TelegramParams;
+
+/// The proc macro and the daemon only understand each other when they come from the same version of the crate.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A question sent from the proc macro to the daemon, as one line of JSON.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Request {
+    /// The version of the crate that the proc macro is from.
+    pub version: String,
+    pub params: TelegramParams,
+    pub question: String,
+    /// The source of the item that the question is in.
+    #[serde(default)]
+    pub item: String,
+}
+
+/// The daemon's reply to a [`Request`], as one line of JSON.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Response {
+    Answer(String),
+    /// The same error that asking directly would have failed with, as far as it can be sent.
+    Error(AskAFriendError),
+    /// The request is from another version of the crate, so the daemon, which is this version, did not ask it.
+    WrongVersion(String),
+}
+
+/// Read a request from a proc macro. If it comes from another version of the crate, its parameters may mean
+/// something else, so the response to send back is returned instead, and the proc macro asks by itself.
+// Only the daemon reads requests, and the proc macro shares this module with it.
+#[allow(dead_code)]
+pub(crate) fn read_request(line: &str) -> Result<Request, Response> {
+    let malformed = |e: serde_json::Error| Response::Error(AskAFriendError::DaemonError(format!("malformed request: {e}")));
+    let request: serde_json::Value = serde_json::from_str(line).map_err(malformed)?;
+    if request.get("version").and_then(|v| v.as_str()) != Some(VERSION) {
+        return Err(Response::WrongVersion(VERSION.to_string()));
+    }
+    serde_json::from_value(request).map_err(malformed)
+}
+
+/// Where the daemon listens: `$PHONE_A_FRIEND_SOCKET` if it is set,
+/// otherwise a socket in the user's runtime directory, or in a private directory in the temporary one.
+///
+/// The proc macro sends the bot token to whatever listens there, so the socket must not be
+/// somewhere that another user could have put one. If the private directory is not private, this is an error.
+pub(crate) fn socket_path() -> Result<PathBuf, String> {
+    if let Some(path) = std::env::var_os("PHONE_A_FRIEND_SOCKET") {
+        return Ok(path.into());
+    }
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(dir).join("phone-a-friend.sock"));
+    }
+
+    // SAFETY: `getuid` has no preconditions, and cannot fail.
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("phone-a-friend-{uid}"));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(error) => return Err(format!("cannot create `{}`: {error}", dir.display())),
+    }
+    let metadata = std::fs::symlink_metadata(&dir).map_err(|e| format!("cannot check `{}`: {e}", dir.display()))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(format!("`{}` is not a directory that only this user can use", dir.display()));
+    }
+    Ok(dir.join("daemon.sock"))
+}
+
+/// Ask the question through the daemon, starting it first if it is not running.
+///
+/// Returns `None` if the daemon could not be reached at all, or is from another version of the crate,
+/// so that the caller can fall back to asking directly.
+// Only the proc macro asks the daemon, and the daemon shares this module with it.
+#[allow(dead_code)]
+pub(crate) fn ask_via_daemon(
+    params: &TelegramParams,
+    query: &str,
+    item: &str,
+) -> Option<Result<String, AskAFriendError>> {
+    let stream = connect_or_start(params)?;
+    exchange(stream, params, query, item)
+}
+
+fn connect_or_start(params: &TelegramParams) -> Option<UnixStream> {
+    let path = match socket_path() {
+        Ok(path) => path,
+        Err(reason) => {
+            println!("Not using the daemon: {reason}");
+            return None;
+        }
+    };
+    if let Ok(stream) = UnixStream::connect(&path) {
+        return Some(stream);
+    }
+
+    // Put the daemon in its own process group, so that it is not killed together with this rustc.
+    let spawned = Command::new(&params.daemon_bin)
+        .arg("daemon")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn();
+    if let Err(error) = spawned {
+        println!("Could not start `{} daemon`: {error}", params.daemon_bin);
+        return None;
+    }
+
+    // Give it a few seconds to start listening.
+    for _ in 0..50 {
+        std::thread::sleep(Duration::from_millis(100));
+        if let Ok(stream) = UnixStream::connect(&path) {
+            return Some(stream);
+        }
+    }
+    None
+}
+
+fn exchange(
+    mut stream: UnixStream,
+    params: &TelegramParams,
+    query: &str,
+    item: &str,
+) -> Option<Result<String, AskAFriendError>> {
+    let request = Request {
+        version: VERSION.to_string(),
+        // The daemon's own connection to Telegram takes care of everything else.
+        params: TelegramParams { use_daemon: false, ..params.clone() },
+        question: query.to_string(),
+        item: item.to_string(),
+    };
+    let mut line = serde_json::to_string(&request).expect("request can always be serialized");
+    line.push('\n');
+    if let Err(e) = stream.write_all(line.as_bytes()) {
+        return Some(Err(AskAFriendError::DaemonError(format!("cannot send the question to the daemon: {e}"))));
+    }
+
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(stream).read_line(&mut line) {
+        return Some(Err(AskAFriendError::DaemonError(format!("cannot read the answer from the daemon: {e}"))));
+    }
+    match serde_json::from_str(&line) {
+        Ok(Response::Answer(answer)) => Some(Ok(answer)),
+        Ok(Response::Error(error)) => Some(Err(error)),
+        Ok(Response::WrongVersion(version)) => {
+            println!("The running phone-a-friend daemon is version {version}, not {VERSION}");
+            None
+        }
+        Err(_) => Some(Err(AskAFriendError::DaemonError(
+            "the daemon closed the connection without answering".to_string(),
+        ))),
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use zbus::zvariant::Value;
+
+use crate::error::AskAFriendError;
+
+/// The action key that notification servers use for the inline reply field.
+const INLINE_REPLY_ACTION: &str = "inline-reply";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    fn close_notification(&self, id: u32) -> zbus::Result<()>;
+
+    fn get_capabilities(&self) -> zbus::Result<Vec<String>>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_replied(&self, id: u32, text: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// Implementation of the "ask friend" feature using desktop notifications as a backend.
+///
+/// When this function is called, it will raise a notification on the session bus
+/// (`org.freedesktop.Notifications`) and wait until it is answered,
+/// either with the inline reply field or by clicking one of the `choices` buttons.
+/// This is mostly useful for local builds, where the "friend" is sitting at the same desk.
+pub(crate) fn ask_friend_via_desktop(
+    params: &DesktopParams,
+    query: &str,
+) -> Result<String, AskAFriendError> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(ask_friend_via_desktop_inner(params, query))
+}
+
+pub(crate) struct DesktopParams {
+    /// If not empty, the notification shows one button for each of these,
+    /// and the clicked one is the answer.
+    pub choices: Vec<String>,
+    pub response_timeout: Duration,
+}
+
+async fn ask_friend_via_desktop_inner(
+    params: &DesktopParams,
+    query: &str,
+) -> Result<String, AskAFriendError> {
+    // Without a session bus (like on a CI machine or over SSH) there is nobody to ask.
+    let connection = zbus::Connection::session()
+        .await
+        .map_err(|e| AskAFriendError::BackendUnavailable(format!("no D-Bus session bus: {e}")))?;
+    let proxy = NotificationsProxy::new(&connection)
+        .await
+        .map_err(|e| AskAFriendError::BackendUnavailable(format!("no notification server: {e}")))?;
+
+    let capabilities = proxy
+        .get_capabilities()
+        .await
+        .map_err(|e| AskAFriendError::BackendUnavailable(format!("no notification server: {e}")))?;
+    let can_reply = capabilities.iter().any(|c| c == INLINE_REPLY_ACTION);
+    if !capabilities.iter().any(|c| c == "actions") {
+        return Err(AskAFriendError::BackendUnavailable(
+            "the notification server does not support actions".to_string(),
+        ));
+    }
+    if params.choices.is_empty() && !can_reply {
+        return Err(AskAFriendError::BackendUnavailable(
+            "the notification server does not support inline replies, so `choices` must be given".to_string(),
+        ));
+    }
+
+    // Actions are given as a flat list of (key, label) pairs.
+    let mut actions: Vec<&str> = vec![];
+    for choice in &params.choices {
+        actions.push(choice);
+        actions.push(choice);
+    }
+    if params.choices.is_empty() {
+        actions.push(INLINE_REPLY_ACTION);
+        actions.push("Answer");
+    }
+
+    let mut hints = HashMap::new();
+    // Critical urgency keeps the notification on screen until it is dealt with.
+    hints.insert("urgency", Value::U8(2));
+    if params.choices.is_empty() {
+        hints.insert("x-kde-reply-placeholder-text", Value::from("Type name"));
+    }
+
+    // Subscribe before the notification is shown, so that a quick answer is not missed.
+    let stream_error = |e: zbus::Error| AskAFriendError::BackendUnavailable(format!("cannot listen for answers: {e}"));
+    let mut actions_invoked = proxy.receive_action_invoked().await.map_err(stream_error)?;
+    let mut replies = proxy.receive_notification_replied().await.map_err(stream_error)?;
+    let mut closed = proxy.receive_notification_closed().await.map_err(stream_error)?;
+
+    let id = proxy
+        .notify("phone-a-friend", 0, "dialog-question", "Phone a friend", query, &actions, hints, 0)
+        .await
+        .map_err(|e| AskAFriendError::SendMessageError(e.to_string()))?;
+
+    let wait = async {
+        loop {
+            // Clicking a button sends `ActionInvoked` and then `NotificationClosed`, which can both be ready at once:
+            // the answer must win over the close, so the branches are checked in order.
+            tokio::select! {
+                biased;
+                Some(signal) = actions_invoked.next() => {
+                    let Ok(args) = signal.args() else { continue };
+                    if args.id == id && params.choices.iter().any(|c| c == args.action_key) {
+                        return Ok(args.action_key.to_string());
+                    }
+                }
+                Some(signal) = replies.next() => {
+                    let Ok(args) = signal.args() else { continue };
+                    if args.id == id {
+                        return Ok(args.text.trim().to_string());
+                    }
+                }
+                Some(signal) = closed.next() => {
+                    let Ok(args) = signal.args() else { continue };
+                    if args.id == id {
+                        return Err(AskAFriendError::Dismissed);
+                    }
+                }
+                else => return Err(AskAFriendError::BackendUnavailable("the session bus went away".to_string())),
+            }
+        }
+    };
+
+    match tokio::time::timeout(params.response_timeout, wait).await {
+        Ok(result) => result,
+        Err(_) => {
+            let _ = proxy.close_notification(id).await;
+            Err(AskAFriendError::Timeout)
+        }
+    }
+}
@@ -1,11 +1,114 @@
-#[derive(Debug)]
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The daemon sends these back to the proc macro, apart from the ones with a `reqwest::Error`: see [`AskAFriendError::sendable`].
+#[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum AskAFriendError {
+    #[serde(skip)]
     NetworkError(reqwest::Error),
     TokenInvalid,
-    SendMessageError,
-    UnknownChatId,
-    ChatClosed,
+    /// The message could not be sent, with the service's explanation of why.
+    SendMessageError(String),
+    UnknownChatId(String),
+    ChatClosed(String),
+    /// The bot was removed from the group chat it should ask in.
+    BotKicked(String),
+    /// The question is longer than the service allows in one message.
+    MessageTooLong(String),
+    /// The question's formatting markup is invalid.
+    CantParseEntities(String),
+    #[serde(skip)]
     APIError(reqwest::Error),
     Timeout,
+    /// Telegram kept answering 429 Too Many Requests, even after waiting and retrying.
+    RateLimited { retry_after: u64 },
+    /// The friend closed the question without answering it.
+    Dismissed,
+    /// The friend asked for the build to be stopped, with `/cancel`.
+    Cancelled,
+    /// The friends' vote ended with these choices tied, and ties are not broken.
+    TiedVote(Vec<String>),
+    /// Not enough of the friends gave the same answer; these are the answers that they gave.
+    Disagreement(Vec<String>),
+    /// The backend cannot be used in this environment, like a desktop backend without a session bus.
+    BackendUnavailable(String),
+    /// The friend's answer cannot be put in place of the magic type, for the given reason.
+    InvalidAnswer(String),
+    /// The question has been written down to the given file, and has not been answered there yet.
+    Pending(String),
+    /// The friend is outside of their hours in the friend directory, and there is nobody else to ask.
+    Unavailable(String),
+    /// The question does not name a friend to ask, and the macro has no friend of its own either.
+    NoFriendNamed,
+    /// Asking through the `phone-a-friend daemon` failed; this is the daemon's (or the connection's) full explanation.
+    DaemonError(String),
     UnknownError(String),
 }
+
+impl AskAFriendError {
+    /// The error message, followed by a `caused by:` line for each of its sources.
+    /// Some errors already repeat their source's message at the end of their own, so those sources are skipped.
+    pub(crate) fn with_sources(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            let line = error.to_string();
+            if !message.ends_with(&line) {
+                message.push_str(&format!("\ncaused by: {line}"));
+            }
+            source = error.source();
+        }
+        message
+    }
+
+    /// The error, in a form that the daemon can send: the errors of its HTTP client become their message.
+    // Only the daemon sends errors, and the proc macro shares this module with it.
+    #[allow(dead_code)]
+    pub(crate) fn sendable(self) -> Self {
+        match self {
+            AskAFriendError::NetworkError(_) | AskAFriendError::APIError(_) => AskAFriendError::DaemonError(self.with_sources()),
+            error => error,
+        }
+    }
+}
+
+/// The messages end up in `compile_error!`s, so each one says what to do about it if there is anything to do.
+impl fmt::Display for AskAFriendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AskAFriendError::*;
+        match self {
+            NetworkError(_) => write!(f, "error communicating with the network; check the internet connection and proxy settings of this machine"),
+            TokenInvalid => write!(f, "the provided token could not be used; check that the bot token (or API credentials) in the attributes is current"),
+            SendMessageError(description) => write!(f, "error sending messages: {description}"),
+            UnknownChatId(description) => write!(f, "the chat_id is not known to the bot ({description}); check the `chat_id`, and make sure the friend has sent /start to the bot"),
+            ChatClosed(description) => write!(f, "the user has blocked the chat with the bot ({description}); ask them to unblock it and send /start again"),
+            BotKicked(description) => write!(f, "the bot is no longer a member of the chat ({description}); add it back to the group"),
+            MessageTooLong(description) => write!(f, "the question is too long to send ({description}); make it shorter"),
+            CantParseEntities(description) => write!(f, "the question's formatting could not be parsed ({description})"),
+            APIError(_) => write!(f, "some error with parsing the API response"),
+            Timeout => write!(f, "user did not provide an answer in time; try again when they are around"),
+            RateLimited { retry_after } => write!(f, "rate limited by the API even after retrying (asked to wait {retry_after}s); try again later"),
+            Dismissed => write!(f, "the question was dismissed without an answer"),
+            Cancelled => write!(f, "the friend cancelled the build with /cancel"),
+            Disagreement(answers) => write!(f, "the friends did not agree on an answer ({}); settle it with them and build again", answers.join(", ")),
+            TiedVote(choices) => write!(f, "the friends' vote was tied between {}; build again to hold another vote", choices.join(", ")),
+            BackendUnavailable(reason) => write!(f, "the backend is not available here ({reason}); use another backend in this environment"),
+            InvalidAnswer(reason) => write!(f, "the answer cannot be used ({reason}); ask again for a type"),
+            Pending(path) => write!(f, "answer this question in `{path}` and commit it"),
+            Unavailable(reason) => write!(f, "{reason}; build again during their hours, or give them a `fallback` or a `default` in the friend directory"),
+            NoFriendNamed => write!(f, "nobody to ask this question; name a friend from the directory, like `PhoneAFriend(alice, \"...\")`, or give a `friend` attribute"),
+            DaemonError(reason) => write!(f, "{reason}"),
+            UnknownError(reason) => write!(f, "unknown error: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for AskAFriendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AskAFriendError::NetworkError(error) | AskAFriendError::APIError(error) => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use proc_macro::{Literal, TokenStream};
+use quote::quote;
+
+use crate::answer::Position;
+use crate::desktop::{ask_friend_via_desktop, DesktopParams};
+use crate::error::AskAFriendError;
+use crate::git_queue::{ask_friend_via_git, GitQueueParams};
+use crate::mastodon::{ask_friend_via_mastodon, MastodonParams};
+use crate::parse_attrs::AttrValue;
+use crate::sms::{ask_friend_via_sms, SmsParams};
+use crate::telegram::{ask_friend_via_tg, TelegramParams};
+use crate::{desktop_params, git_params, mastodon_params, sms_params, telegram_params};
+
+/// The friend directory, in the crate root, next to `Cargo.toml`.
+const DIRECTORY_FILE: &str = "phone-a-friend.toml";
+
+/// The keys in a directory entry that say when the friend can be asked, rather than how.
+const SCHEDULE_KEYS: &[&str] = &["hours", "days", "timezone", "fallback"];
+
+/// What the `timezone` and `hours` of a schedule must look like, for the errors about them.
+const TIMEZONE_EXPECTED: &str = "a time zone name, like \"Europe/Berlin\"";
+const HOURS_EXPECTED: &str = "a range of times, like \"09:00-17:00\"";
+
+/// A friend to ask, with everything needed to ask them through their backend.
+pub(crate) struct Friend {
+    backend: Backend,
+    /// When the friend can be asked, if the directory says.
+    availability: Option<Availability>,
+}
+
+enum Backend {
+    Telegram(Box<TelegramParams>),
+    Sms(SmsParams),
+    Mastodon(MastodonParams),
+    Desktop(DesktopParams),
+    Git(GitQueueParams),
+}
+
+/// A friend's entry in the directory.
+pub(crate) struct Entry {
+    pub backend: String,
+    /// The attributes for the backend's macro.
+    pub attrs: HashMap<String, AttrValue>,
+    pub availability: Option<Availability>,
+}
+
+/// When a friend from the directory can be asked, and what to do instead at other times.
+pub(crate) struct Availability {
+    name: String,
+    timezone: Tz,
+    /// The days that the friend can be asked on; if empty, every day.
+    days: Vec<Weekday>,
+    /// The time of day that the friend can be asked from, and until. This may go past midnight, like 22:00-06:00.
+    hours: Option<(NaiveTime, NaiveTime)>,
+    /// The friend to ask instead.
+    fallback: Option<String>,
+    /// The answer to use instead, if there is no fallback.
+    default: Option<String>,
+}
+
+/// What to do instead of asking a friend who is away.
+pub(crate) enum Away {
+    /// Ask this other friend from the directory.
+    Fallback(String),
+    /// Use this answer.
+    Default(String),
+    /// Fail, with this explanation.
+    Offline(String),
+}
+
+impl Friend {
+    /// Get a friend who is asked through the named backend, from the attributes for that backend.
+    pub(crate) fn new(backend: &str, attrs: &HashMap<String, AttrValue>) -> Result<Friend, TokenStream> {
+        let backend = match backend {
+            "telegram" => Backend::Telegram(Box::new(telegram_params(attrs)?)),
+            "sms" => Backend::Sms(sms_params(attrs)?),
+            "mastodon" => Backend::Mastodon(mastodon_params(attrs)?),
+            "desktop" => Backend::Desktop(desktop_params(attrs)?),
+            "git" => Backend::Git(git_params(attrs)?),
+            _ => {
+                let message = format!(
+                    "unknown backend `{backend}`; expected \"telegram\", \"sms\", \"mastodon\", \"desktop\" or \"git\""
+                );
+                return Err(quote! {
+                    compile_error!(#message);
+                }
+                .into());
+            }
+        };
+        Ok(Friend { backend, availability: None })
+    }
+
+    /// Get a friend from their entry in the directory.
+    pub(crate) fn from_entry(entry: Entry) -> Result<Friend, TokenStream> {
+        let friend = Friend::new(&entry.backend, &entry.attrs)?;
+        Ok(Friend { availability: entry.availability, ..friend })
+    }
+
+    /// The friend to ask instead when this one is away, if there is one.
+    pub(crate) fn fallback(&self) -> Option<&str> {
+        self.availability.as_ref()?.fallback.as_deref()
+    }
+
+    /// Check whether the friend can be asked at the given time, and what to do instead if not.
+    pub(crate) fn away(&self, now: DateTime<Utc>) -> Option<Away> {
+        let availability = self.availability.as_ref()?;
+        if availability.is_available(now) {
+            return None;
+        }
+        Some(match (&availability.fallback, &availability.default) {
+            (Some(fallback), _) => Away::Fallback(fallback.clone()),
+            (None, Some(default)) => Away::Default(default.clone()),
+            (None, None) => Away::Offline(format!("`{}` is away ({})", availability.name, availability.describe())),
+        })
+    }
+
+    /// Ask the friend the question. Only some backends can show the `item` that it is about,
+    /// and only some can ask again when the answer cannot go in the question's `position`.
+    pub(crate) fn ask(&mut self, question: &str, item: &str, position: Position) -> Result<String, AskAFriendError> {
+        match &mut self.backend {
+            Backend::Telegram(params) => ask_friend_via_tg(params, question, item, position),
+            Backend::Sms(params) => ask_friend_via_sms(params, question),
+            Backend::Mastodon(params) => ask_friend_via_mastodon(params, question),
+            Backend::Desktop(params) => ask_friend_via_desktop(params, question),
+            Backend::Git(params) => ask_friend_via_git(params, question, item),
+        }
+    }
+}
+
+/// Ask the question to the named friend from the directory, or to `default` if no friend is named.
+/// If that friend is away, the question goes to their fallback instead, or is answered with their default.
+pub(crate) fn ask_available(
+    named: &mut HashMap<String, Friend>,
+    default: Option<&mut Friend>,
+    name: Option<&str>,
+    question: &str,
+    item: &str,
+    position: Position,
+) -> Result<String, AskAFriendError> {
+    let mut friend = match name {
+        Some(name) => named.get_mut(name),
+        None => default,
+    };
+    let mut asked: Vec<String> = name.into_iter().map(str::to_string).collect();
+    loop {
+        let Some(current) = friend else {
+            return Err(AskAFriendError::NoFriendNamed);
+        };
+        match current.away(Utc::now()) {
+            None => return current.ask(question, item, position),
+            Some(Away::Default(answer)) => return Ok(answer),
+            Some(Away::Offline(reason)) => return Err(AskAFriendError::Unavailable(reason)),
+            // Everybody on the way has been tried, and they are all away.
+            Some(Away::Fallback(next)) if asked.contains(&next) => {
+                return Err(AskAFriendError::Unavailable(format!("`{}` are all away", asked.join("`, `"))));
+            }
+            Some(Away::Fallback(next)) => {
+                friend = named.get_mut(&next);
+                asked.push(next);
+            }
+        }
+    }
+}
+
+impl Availability {
+    fn is_available(&self, now: DateTime<Utc>) -> bool {
+        let now = now.with_timezone(&self.timezone);
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let time = now.time();
+        match self.hours {
+            None => on(now.weekday()),
+            Some((from, until)) if from <= until => on(now.weekday()) && from <= time && time < until,
+            // Hours that go past midnight belong to the day that they start on.
+            Some((from, until)) => (on(now.weekday()) && from <= time) || (on(now.weekday().pred()) && time < until),
+        }
+    }
+
+    /// Describe the schedule for error messages, like "hours 09:00-17:00 on Mon, Tue in Europe/Berlin".
+    fn describe(&self) -> String {
+        let mut description = String::new();
+        if let Some((from, until)) = self.hours {
+            description += &format!("hours {}-{} ", from.format("%H:%M"), until.format("%H:%M"));
+        }
+        if !self.days.is_empty() {
+            let days: Vec<String> = self.days.iter().map(|day| day.to_string()).collect();
+            description += &format!("on {} ", days.join(", "));
+        }
+        description + &format!("in {}", self.timezone)
+    }
+}
+
+/// Look up a friend's `[friends.<name>]` table in the directory, and get the backend that they are asked through,
+/// with the attributes for it.
+pub(crate) fn lookup(name: &str) -> Result<Entry, String> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    let path = PathBuf::from(manifest_dir).join(DIRECTORY_FILE);
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("cannot read the friend directory `{}`: {e}", path.display()))?;
+    let directory: toml::Table = toml::from_str(&contents)
+        .map_err(|e| format!("cannot parse the friend directory `{}`: {e}", path.display()))?;
+
+    let entry = directory
+        .get("friends")
+        .and_then(|friends| friends.get(name))
+        .and_then(|entry| entry.as_table())
+        .ok_or_else(|| format!("there is no `[friends.{name}]` in `{DIRECTORY_FILE}`"))?;
+    let backend = entry
+        .get("backend")
+        .and_then(|backend| backend.as_str())
+        .ok_or_else(|| format!("`[friends.{name}]` in `{DIRECTORY_FILE}` must have a `backend`"))?;
+
+    let availability = availability(name, entry)?;
+    let mut attrs = HashMap::new();
+    for (key, value) in entry.iter().filter(|(key, _)| *key != "backend" && !SCHEDULE_KEYS.contains(&key.as_str())) {
+        let attr = match value {
+            toml::Value::Array(values) => values.iter().map(literal).collect::<Option<_>>().map(AttrValue::List),
+            value => literal(value).map(AttrValue::Literal),
+        };
+        let attr = attr.ok_or_else(|| {
+            format!("`{key}` of `[friends.{name}]` in `{DIRECTORY_FILE}` must be a string, an integer or a list of those")
+        })?;
+        attrs.insert(key.clone(), attr);
+    }
+    Ok(Entry { backend: backend.to_string(), attrs, availability })
+}
+
+/// Get the friend's schedule from their directory entry: `hours = "09:00-17:00"`, `days = ["Mon", "Tue"]`,
+/// and `timezone = "Europe/Berlin"` (UTC by default). Outside of it, the friend's `fallback` is asked instead,
+/// or else their `default` answer is used, or else the question fails.
+fn availability(name: &str, entry: &toml::Table) -> Result<Option<Availability>, String> {
+    let invalid = |key: &str, expected: &str| format!("`{key}` of `[friends.{name}]` in `{DIRECTORY_FILE}` must be {expected}");
+    if !["hours", "days"].iter().any(|key| entry.contains_key(*key)) {
+        // Without a schedule, the friend is always available, so these would never be used.
+        return match ["timezone", "fallback"].iter().find(|key| entry.contains_key(**key)) {
+            Some(key) => Err(format!(
+                "`{key}` of `[friends.{name}]` in `{DIRECTORY_FILE}` needs `hours` or `days`, to say when the friend is away"
+            )),
+            None => Ok(None),
+        };
+    }
+    // A value of the wrong type is reported like a wrong value, instead of being left out.
+    let string = |key: &str, expected: &str| match entry.get(key) {
+        Some(value) => value.as_str().map(Some).ok_or_else(|| invalid(key, expected)),
+        None => Ok(None),
+    };
+
+    let timezone = match string("timezone", TIMEZONE_EXPECTED)? {
+        Some(timezone) => timezone.parse().map_err(|_| invalid("timezone", TIMEZONE_EXPECTED))?,
+        None => Tz::UTC,
+    };
+    let days = match entry.get("days") {
+        Some(days) => days
+            .as_array()
+            .and_then(|days| days.iter().map(|day| day.as_str()?.parse().ok()).collect::<Option<_>>())
+            .ok_or_else(|| invalid("days", "a list of weekdays, like [\"Mon\", \"Tue\"]"))?,
+        None => vec![],
+    };
+    let hours = match string("hours", HOURS_EXPECTED)? {
+        Some(hours) => {
+            let (from, until) = hours
+                .split_once('-')
+                .and_then(|(from, until)| {
+                    let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+                    Some((time(from)?, time(until)?))
+                })
+                .ok_or_else(|| invalid("hours", HOURS_EXPECTED))?;
+            Some((from, until))
+        }
+        None => None,
+    };
+
+    Ok(Some(Availability {
+        name: name.to_string(),
+        timezone,
+        days,
+        hours,
+        fallback: string("fallback", "the name of another friend")?.map(str::to_string),
+        default: string("default", "a string")?.map(str::to_string),
+    }))
+}
+
+/// Turn a value from the directory into the literal that it would be in an attribute group.
+fn literal(value: &toml::Value) -> Option<Literal> {
+    match value {
+        toml::Value::String(string) => Some(Literal::string(string)),
+        toml::Value::Integer(integer) => Some(Literal::i64_unsuffixed(*integer)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn night_shift(days: Vec<Weekday>) -> Availability {
+        Availability {
+            name: "alice".to_string(),
+            timezone: Tz::UTC,
+            days,
+            hours: Some((NaiveTime::from_hms_opt(22, 0, 0).unwrap(), NaiveTime::from_hms_opt(6, 0, 0).unwrap())),
+            fallback: None,
+            default: None,
+        }
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        // 2024-01-01 is a Monday.
+        Utc.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn hours_go_past_midnight() {
+        let availability = night_shift(vec![]);
+        assert!(availability.is_available(at(1, 23)));
+        assert!(availability.is_available(at(2, 3)));
+        assert!(!availability.is_available(at(2, 6)));
+        assert!(!availability.is_available(at(2, 12)));
+    }
+
+    #[test]
+    fn hours_past_midnight_belong_to_the_day_they_start_on() {
+        let availability = night_shift(vec![Weekday::Mon]);
+        assert!(availability.is_available(at(1, 23)));
+        // Tuesday morning is still Monday's night.
+        assert!(availability.is_available(at(2, 3)));
+        assert!(!availability.is_available(at(2, 23)));
+        // Monday morning is Sunday's night.
+        assert!(!availability.is_available(at(1, 3)));
+    }
+
+    #[test]
+    fn hours_are_in_the_friends_time_zone() {
+        let availability = Availability { timezone: chrono_tz::Europe::Berlin, ..night_shift(vec![]) };
+        // 21:30 UTC is 22:30 in Berlin in winter.
+        assert!(availability.is_available(Utc.with_ymd_and_hms(2024, 1, 1, 21, 30, 0).unwrap()));
+        assert!(!availability.is_available(Utc.with_ymd_and_hms(2024, 1, 2, 5, 30, 0).unwrap()));
+    }
+}
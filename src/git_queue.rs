@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AskAFriendError;
+use crate::item_context::question_line;
+
+/// Implementation of the "ask friend" feature using files in the repository as a backend.
+///
+/// Instead of talking to a friend directly, the question is written down
+/// to `<dir>/pending/<id>.toml`, and the build fails until someone fills in its `answer`
+/// and commits the file. The next build then finds the answer there.
+/// This needs no network at all, so it also works in air-gapped environments.
+pub(crate) fn ask_friend_via_git(params: &GitQueueParams, query: &str, item: &str) -> Result<String, AskAFriendError> {
+    let line = question_line(item).unwrap_or_default();
+    let path = params.pending_dir().join(format!("{}.toml", question_id(query, line)));
+
+    if path.exists() {
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| AskAFriendError::UnknownError(format!("cannot read {}: {e}", path.display())))?;
+        let file: QuestionFile = toml::from_str(&contents)
+            .map_err(|e| AskAFriendError::UnknownError(format!("cannot parse {}: {e}", path.display())))?;
+        return match file.answer.map(|a| a.trim().to_string()) {
+            Some(answer) if !answer.is_empty() => Ok(answer),
+            _ => Err(AskAFriendError::Pending(relative_path(&path))),
+        };
+    }
+
+    std::fs::create_dir_all(params.pending_dir())
+        .map_err(|e| AskAFriendError::UnknownError(format!("cannot create the pending directory: {e}")))?;
+    let file = QuestionFile {
+        question: query.to_string(),
+        crate_name: std::env::var("CARGO_PKG_NAME").ok(),
+        answer: Some(String::new()),
+    };
+    let contents = format!(
+        "# A build is waiting for the answer to this question.\n\
+         # Write it into `answer` below, then commit this file.\n\n{}",
+        toml::to_string(&file).expect("question file can always be serialized")
+    );
+    std::fs::write(&path, contents)
+        .map_err(|e| AskAFriendError::UnknownError(format!("cannot write {}: {e}", path.display())))?;
+    Err(AskAFriendError::Pending(relative_path(&path)))
+}
+
+pub(crate) struct GitQueueParams {
+    /// The queue directory, relative to the crate's manifest directory.
+    pub dir: PathBuf,
+}
+
+impl GitQueueParams {
+    fn pending_dir(&self) -> PathBuf {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+        Path::new(&manifest_dir).join(&self.dir).join("pending")
+    }
+}
+
+/// Contents of one `pending/*.toml` file.
+#[derive(Serialize, Deserialize)]
+struct QuestionFile {
+    question: String,
+    #[serde(rename = "crate", skip_serializing_if = "Option::is_none")]
+    crate_name: Option<String>,
+    answer: Option<String>,
+}
+
+/// Show the path relative to the manifest directory, which is how it appears in the repository.
+fn relative_path(path: &Path) -> String {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    path.strip_prefix(&manifest_dir).unwrap_or(path).display().to_string()
+}
+
+/// Get a file name for the question that stays the same between builds:
+/// a few words of the question, followed by a hash of all of it and of the source line that asks it.
+/// So the same question asked in two places gets two files, which stay put when the code around them changes.
+fn question_id(query: &str, line: &str) -> String {
+    let slug = query
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .take(6)
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    // 32-bit FNV-1a, because the standard library's hasher may change between Rust versions.
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in query.bytes().chain([b'\n']).chain(line.trim().bytes()) {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    if slug.is_empty() {
+        format!("{hash:08x}")
+    } else {
+        format!("{slug}-{hash:08x}")
+    }
+}
@@ -0,0 +1,100 @@
+use proc_macro::{Delimiter, Span, TokenStream, TokenTree};
+
+/// Put at the end of the question's line, so that the friend can tell which part of the item it is about.
+const MARKER: &str = "  // <- this one";
+
+/// Longer items are cut down to this many lines around the question.
+const MAX_LINES: usize = 30;
+
+/// Get the source of the item in the macro's `body` that the question at `question` is in,
+/// with the line of the question marked.
+///
+/// Returns an empty string if no item in the body has the question in it.
+pub fn item_context(body: &TokenStream, question: Span) -> String {
+    let question_line = question.start().line();
+
+    // An item ends with a semicolon, or with its braces.
+    let mut items: Vec<Vec<TokenTree>> = vec![vec![]];
+    for token in body.clone() {
+        let ends_item = match &token {
+            TokenTree::Punct(punct) => punct.as_char() == ';',
+            TokenTree::Group(group) => group.delimiter() == Delimiter::Brace,
+            _ => false,
+        };
+        items.last_mut().unwrap().push(token);
+        if ends_item {
+            items.push(vec![]);
+        }
+    }
+
+    let item = items.iter().find(|item| match (item.first(), item.last()) {
+        (Some(first), Some(last)) => {
+            first.span().start().line() <= question_line && question_line <= last.span().end().line()
+        }
+        _ => false,
+    });
+    match item {
+        Some(item) => render(item, question_line),
+        None => String::new(),
+    }
+}
+
+/// Get the line of the question from an item given by [`item_context`], without its marker.
+pub fn question_line(item: &str) -> Option<&str> {
+    item.lines().find_map(|line| line.strip_suffix(MARKER))
+}
+
+/// Lay the tokens out like they are in the source file, and mark the line of the question.
+fn render(item: &[TokenTree], question_line: usize) -> String {
+    let first_line = item[0].span().start().line();
+    let mut text = String::new();
+    // Columns count from 1.
+    let (mut line, mut column) = (first_line, 1);
+    for token in item {
+        let start = token.span().start();
+        if start.line() > line {
+            text += &"\n".repeat(start.line() - line);
+            column = 1;
+        } else if start.line() < line || start.column() < column {
+            // The tokens did not come from the source file as they are, so their positions mean nothing.
+            text.push(' ');
+        }
+        text += &" ".repeat(start.column().saturating_sub(column));
+        // A group's source text has everything in it, laid out as it was written.
+        text += &token.span().source_text().unwrap_or_else(|| token.to_string());
+        let end = token.span().end();
+        (line, column) = (end.line(), end.column());
+    }
+
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let question = question_line.saturating_sub(first_line);
+    if let Some(line) = lines.get_mut(question) {
+        line.push_str(MARKER);
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    for line in &mut lines {
+        *line = line.get(indent..).unwrap_or_else(|| line.trim_start()).to_string();
+    }
+
+    let total = lines.len();
+    if total > MAX_LINES {
+        let start = question.saturating_sub(MAX_LINES / 2).min(total - MAX_LINES);
+        let end = start + MAX_LINES;
+        let mut shown = vec![];
+        if start > 0 {
+            shown.push("...".to_string());
+        }
+        shown.extend(lines.drain(start..end));
+        if end < total {
+            shown.push("...".to_string());
+        }
+        lines = shown;
+    }
+    lines.join("\n")
+}
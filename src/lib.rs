@@ -1,102 +1,768 @@
 extern crate proc_macro;
 use litrs::StringLit;
-use proc_macro::{Group, Ident, TokenStream, TokenTree};
+use proc_macro::{Delimiter, Group, Ident, Literal, Span, TokenStream, TokenTree};
 use quote::{quote, quote_spanned};
 
+mod answer;
+#[cfg(unix)]
+mod daemon;
+mod desktop;
 mod error;
+mod friends;
+mod git_queue;
+mod item_context;
+mod mastodon;
 mod parse_attrs;
+mod sms;
 mod telegram;
-use crate::telegram::TelegramParams;
+use crate::answer::{check_replacement, Position};
+use crate::desktop::DesktopParams;
+use crate::error::AskAFriendError;
+use crate::friends::Friend;
+use crate::git_queue::GitQueueParams;
+use crate::item_context::item_context;
+use crate::parse_attrs::AttrValue;
+use crate::mastodon::MastodonParams;
+use crate::sms::SmsParams;
+use crate::telegram::api::Connection;
+use crate::telegram::poll::{PollParams, TieBreak};
+use crate::telegram::{AllowedUser, TelegramParams};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::time::Duration;
-use crate::telegram::ask_friend_via_tg;
 
 /// This proc macro allows you to call a friend to help you specify the type of a struct's field.
 ///
 /// When the magic type `PhoneAFriend(...)` is used,
 /// it will be replaced with u32.
+///
+/// The questions are asked through the `phone-a-friend daemon` (from `cargo install phone-a-friend`),
+/// which is started if it is not running, so that parallel rustc processes do not fight over the bot's updates.
+/// If it cannot be started, the bot is polled directly; `daemon = "off"` always does that,
+/// and `daemon_bin` gives the path of the daemon executable. The daemon only runs on Unix: elsewhere, the bot is always polled directly.
+///
+/// With `webhook_url = "https://..."`, updates are pushed by Telegram instead of polled:
+/// the daemon's receiver listens on `webhook_listen` (by default `127.0.0.1:8080`), which the public URL must forward to,
+/// and the webhook is deleted again when the questions are answered. This needs the daemon, so it cannot be used with `daemon = "off"`,
+/// or on other systems than Unix.
+///
+/// `api_base = "http://localhost:8081"` uses a self-hosted Bot API server (or a mock) instead of Telegram's.
+/// Behind a firewall, `proxy = "http://proxy.example.com:3128"` (or `socks5://...`) sends all the requests through a proxy,
+/// and `ca_cert = "certs/proxy.pem"` (relative to the crate root) is a certificate to trust besides the system's.
+/// The certificate is read when the crate is compiled, and Cargo does not know about the file:
+/// after changing it, `touch` the file with the macro so that the new one is used.
+///
+/// In a group chat (with a negative `chat_id`), `allowed_users = [123, "alice"]` lists the user IDs or usernames
+/// whose replies are accepted; anybody else who replies is told that they may not answer.
+/// To keep a busy group readable, `message_thread_id` sends everything into that forum topic,
+/// and `thread = "build"` sends the questions of each build as replies to a "Build of <crate> <version> needs help" message
+/// (on Unix only).
+///
+/// Each question is shown below the source of the item it is in, with its line marked.
+/// The answer is a type, like `u32` or `HashMap<String, Vec<u8>>`, or whole items, like an impl block,
+/// for a question that is asked where items go. An answer that is neither is rejected with the reason, and the friend can reply again;
+/// an accepted one is marked on the question.
+/// For `edit_grace` seconds after the first answer (5 by default), the friend can still fix a typo in it by editing it;
+/// when several friends are asked at once, that is after enough of them first give the same answer.
+/// When the build is interrupted while it waits, the questions are edited to say that no answer is needed anymore;
+/// questions left open by a build that was killed are cancelled like that by the next one.
+///
+/// Instead of a `chat_id`, `friends = [111, 222, 333]` gives the chats of several friends to ask in turn:
+/// when one of them does not answer in their share of the `response_timeout`,
+/// the question is passed on to the next one, who is told who was asked before.
+/// With `ask_friends = "all"`, they are all asked at once, and the first answer is used;
+/// `consensus = 2` waits for two of them to give the same answer instead,
+/// and fails the build with all the answers if they cannot agree.
+///
+/// The friend has `response_timeout` seconds (60 by default) to answer.
+/// With `remind_every = 120`, an unanswered question is brought up again every 120 seconds,
+/// and the friend is warned 30 seconds before the build fails, or before the question is passed on to the next friend.
+///
+/// With `choices = ["u32", "u64"]`, the answer must be one of those.
+/// In a group, `poll = "on"` lets the friends vote on the choices in a poll instead;
+/// it is closed when `poll_quorum` friends have voted or after `poll_deadline` seconds (60 by default),
+/// and a tie goes to the first of the tied choices, unless `tie_break` is `"random"` or `"fail"`.
+///
+/// Instead of answering, the friend can reply `/skip` (or `/default`) to use the type given as `default = "u32"`,
+/// `/cancel` to fail the build, `/pending` to list the questions waiting for them, or `/help`.
+/// `/pending` lists the questions of every build that is running, whether it asks through the daemon or not.
 #[proc_macro]
 pub fn phone_a_friend_telegram(body: TokenStream) -> TokenStream {
+    let (attrs, body) = match split_attrs(body, Some("`token` and `chat_id` (or `friends`), or a `friend`")) {
+        Ok(result) => result,
+        Err(error) => return error,
+    };
+    match backend_friend(Some("telegram"), &attrs) {
+        Ok(friend) => expand(body, friend),
+        Err(error) => error,
+    }
+}
+
+/// Get the parameters of `phone_a_friend_telegram!` from its attributes.
+fn telegram_params(attrs: &HashMap<String, AttrValue>) -> Result<TelegramParams, TokenStream> {
+    // Assert that there must be attributes:
+    // - token: a string,
+    // - chat_id: an integer, unless there is a list of friends to ask instead
+    let token: String = require_string_attr(attrs, "token")?;
+    println!("Token: {token}");
+    let friends: Vec<i64> = optional_int_list_attr(attrs, "friends")?.unwrap_or_default();
+    let fan_out = fan_out_attrs(attrs, &friends)?;
+    let chat_id: i64 = match friends.first() {
+        Some(&first) => first,
+        None => require_int_attr(attrs, "chat_id")?,
+    };
+    println!("Chat ID: {chat_id}");
+
+    // The daemon is used unless `daemon = "off"` is given; `daemon_bin` is where to find it if it is not running.
+    let daemon = optional_string_attr(attrs, "daemon")?;
+    let use_daemon = cfg!(unix) && daemon.as_deref() != Some("off");
+    let daemon_bin = optional_string_attr(attrs, "daemon_bin")?.unwrap_or_else(|| "phone-a-friend".to_string());
+
+    let webhook_url = optional_string_attr(attrs, "webhook_url")?;
+    let webhook_listen = optional_string_attr(attrs, "webhook_listen")?.unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    if let Err(e) = webhook_listen.parse::<std::net::SocketAddr>() {
+        let message = format!("`webhook_listen` must be an address like `127.0.0.1:8080`, not `{webhook_listen}`: {e}");
+        return Err(quote! {
+            compile_error!(#message);
+        }
+        .into());
+    }
+    if webhook_url.is_some() && !cfg!(unix) {
+        return Err(quote! {
+            compile_error!("`webhook_url` needs the daemon, which only runs on Unix");
+        }
+        .into());
+    }
+    if webhook_url.is_some() && !use_daemon {
+        return Err(quote! {
+            compile_error!("`webhook_url` needs the daemon, so it cannot be used with `daemon = \"off\"`");
+        }
+        .into());
+    }
+
+    let allowed_users = allowed_users_attr(attrs)?;
+    let connection = connection_attrs(attrs)?;
+
+    let default = optional_string_attr(attrs, "default")?;
+    if let Some(Err(reason)) = default.as_deref().map(|default| check_replacement(default, None)) {
+        let message = format!("`default` must be a type: {reason}");
+        return Err(quote! {
+            compile_error!(#message);
+        }
+        .into());
+    }
+
+    let choices = optional_string_list_attr(attrs, "choices")?.unwrap_or_default();
+    if let Some(Err(reason)) = choices.iter().map(|choice| check_replacement(choice, None)).find(Result::is_err) {
+        let message = format!("each of the `choices` must be a type: {reason}");
+        return Err(quote! {
+            compile_error!(#message);
+        }
+        .into());
+    }
+    let poll = poll_attrs(attrs, &choices)?;
+
+    let response_timeout = Duration::from_secs(optional_int_attr(attrs, "response_timeout")?.unwrap_or(60));
+    let remind_every = optional_int_attr(attrs, "remind_every")?.map(Duration::from_secs);
+    let edit_grace = Duration::from_secs(optional_int_attr(attrs, "edit_grace")?.unwrap_or(5));
+
+    let message_thread_id = optional_int_attr(attrs, "message_thread_id")?;
+    let build_thread = match optional_string_attr(attrs, "thread")?.as_deref() {
+        None => None,
+        // Cargo starts a rustc for each crate, so all the questions of one `cargo build` share its process ID.
+        #[cfg(unix)]
+        Some("build") => Some(std::os::unix::process::parent_id()),
+        #[cfg(not(unix))]
+        Some("build") => {
+            return Err(quote! {
+                compile_error!("`thread = \"build\"` is only supported on Unix");
+            }
+            .into())
+        }
+        Some(_) => {
+            return Err(quote! {
+                compile_error!("expected `thread = \"build\"`");
+            }
+            .into())
+        }
+    };
+
+    Ok(TelegramParams {
+        token, connection, api: None, chat_id, friends, fan_out, is_token_valid: false, response_timeout, remind_every, passed_on_to: None,
+        edit_grace,
+        use_daemon, daemon_bin,
+        webhook_url, webhook_listen, allowed_users, choices, poll, default, message_thread_id,
+        crate_name: std::env::var("CARGO_PKG_NAME").unwrap_or_else(|_| "unknown crate".to_string()),
+        crate_version: std::env::var("CARGO_PKG_VERSION").unwrap_or_default(),
+        build_thread,
+        position: Position::Type,
+    })
+}
+
+/// Like `phone_a_friend_telegram!`, but the friend is asked by SMS,
+/// through a Twilio-compatible `Messages` REST API.
+///
+/// Required attributes are `account_sid`, `auth_token`, `from` and `to` (the friend's number).
+/// The optional `api_base` can point to a local stand-in instead of `https://api.twilio.com`,
+/// `response_timeout` is in seconds,
+/// and `max_parts` limits how many messages (at most 99) a long question may be split into.
+#[proc_macro]
+pub fn phone_a_friend_sms(body: TokenStream) -> TokenStream {
+    let (attrs, body) = match split_attrs(body, Some("`account_sid`, `auth_token`, `from` and `to`, or a `friend`")) {
+        Ok(result) => result,
+        Err(error) => return error,
+    };
+    match backend_friend(Some("sms"), &attrs) {
+        Ok(friend) => expand(body, friend),
+        Err(error) => error,
+    }
+}
+
+/// Get how to reach the Bot API from the `api_base`, `proxy` and `ca_cert` attributes, and check that it can be done.
+fn connection_attrs(attrs: &HashMap<String, AttrValue>) -> Result<Connection, TokenStream> {
+    let api_base = optional_string_attr(attrs, "api_base")?;
+    if let Some(api_base) = api_base.as_deref().filter(|base| !base.starts_with("http://") && !base.starts_with("https://")) {
+        let message = format!("`api_base` must be an http:// or https:// URL, not `{api_base}`");
+        return Err(quote! {
+            compile_error!(#message);
+        }
+        .into());
+    }
+    let ca_cert = match optional_string_attr(attrs, "ca_cert")? {
+        Some(path) => {
+            let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+            let path = std::path::Path::new(&manifest_dir).join(path);
+            match std::fs::read_to_string(&path) {
+                Ok(pem) => Some(pem),
+                Err(e) => {
+                    let message = format!("cannot read the `ca_cert` file `{}`: {e}", path.display());
+                    return Err(quote! {
+                        compile_error!(#message);
+                    }
+                    .into());
+                }
+            }
+        }
+        None => None,
+    };
+    let connection = Connection { api_base, proxy: optional_string_attr(attrs, "proxy")?, ca_cert };
+    if let Err(message) = connection.client() {
+        return Err(quote! {
+            compile_error!(#message);
+        }
+        .into());
+    }
+    Ok(connection)
+}
+
+/// Get the parameters of `phone_a_friend_sms!` from its attributes.
+fn sms_params(attrs: &HashMap<String, AttrValue>) -> Result<SmsParams, TokenStream> {
+    // Each part ends with a counter like " (12/99)", and room is only left for two digits.
+    let max_parts = optional_int_attr(attrs, "max_parts")?.unwrap_or(4);
+    if max_parts > 99 {
+        return Err(quote! {
+            compile_error!("`max_parts` can be at most 99");
+        }
+        .into());
+    }
+    Ok(SmsParams {
+        api_base: optional_string_attr(attrs, "api_base")?
+            .unwrap_or_else(|| "https://api.twilio.com".to_string()),
+        account_sid: require_string_attr(attrs, "account_sid")?,
+        auth_token: require_string_attr(attrs, "auth_token")?,
+        from: require_string_attr(attrs, "from")?,
+        to: require_string_attr(attrs, "to")?,
+        max_parts,
+        poll_interval: Duration::from_secs(5),
+        response_timeout: Duration::from_secs(optional_int_attr(attrs, "response_timeout")?.unwrap_or(300)),
+    })
+}
+
+/// Like `phone_a_friend_telegram!`, but the friend is asked on Mastodon (or another compatible server),
+/// with a direct message mentioning their account.
+///
+/// Required attributes are `instance` (the bot account's server, like `"https://mastodon.social"`),
+/// `access_token` and `account` (the friend, like `"@friend@example.social"`).
+/// The optional `response_timeout` is in seconds.
+#[proc_macro]
+pub fn phone_a_friend_mastodon(body: TokenStream) -> TokenStream {
+    let (attrs, body) = match split_attrs(body, Some("`instance`, `access_token` and `account`, or a `friend`")) {
+        Ok(result) => result,
+        Err(error) => return error,
+    };
+    match backend_friend(Some("mastodon"), &attrs) {
+        Ok(friend) => expand(body, friend),
+        Err(error) => error,
+    }
+}
+
+/// Get the parameters of `phone_a_friend_mastodon!` from its attributes.
+fn mastodon_params(attrs: &HashMap<String, AttrValue>) -> Result<MastodonParams, TokenStream> {
+    Ok(MastodonParams {
+        instance: require_string_attr(attrs, "instance")?,
+        access_token: require_string_attr(attrs, "access_token")?,
+        account: require_string_attr(attrs, "account")?,
+        is_token_valid: false,
+        poll_interval: Duration::from_secs(5),
+        response_timeout: Duration::from_secs(optional_int_attr(attrs, "response_timeout")?.unwrap_or(300)),
+    })
+}
+
+/// Like `phone_a_friend_telegram!`, but the question is shown as a desktop notification
+/// through the freedesktop `org.freedesktop.Notifications` D-Bus service.
+///
+/// The attribute group may be empty (`[]`). If the notification server has no inline reply field,
+/// `choices = ["u32", "u64"]` must be given, and each choice becomes a button.
+/// The optional `response_timeout` is in seconds.
+#[proc_macro]
+pub fn phone_a_friend_desktop(body: TokenStream) -> TokenStream {
+    let (attrs, body) = match split_attrs(body, None) {
+        Ok(result) => result,
+        Err(error) => return error,
+    };
+    match backend_friend(Some("desktop"), &attrs) {
+        Ok(friend) => expand(body, friend),
+        Err(error) => error,
+    }
+}
+
+/// Get the parameters of `phone_a_friend_desktop!` from its attributes.
+fn desktop_params(attrs: &HashMap<String, AttrValue>) -> Result<DesktopParams, TokenStream> {
+    Ok(DesktopParams {
+        choices: optional_string_list_attr(attrs, "choices")?.unwrap_or_default(),
+        response_timeout: Duration::from_secs(optional_int_attr(attrs, "response_timeout")?.unwrap_or(120)),
+    })
+}
+
+/// Like `phone_a_friend_telegram!`, but the questions are left as files in the repository
+/// for a friend to answer asynchronously, which works even without network access.
+///
+/// Each unanswered question is written to `phone-a-friend/pending/<id>.toml` and fails the build.
+/// Once a friend fills in the `answer` there and commits it, the next build uses that answer.
+/// The attribute group may be empty (`[]`); the optional `dir` changes the queue directory,
+/// relative to the crate root.
+#[proc_macro]
+pub fn phone_a_friend_git(body: TokenStream) -> TokenStream {
+    let (attrs, body) = match split_attrs(body, None) {
+        Ok(result) => result,
+        Err(error) => return error,
+    };
+    match backend_friend(Some("git"), &attrs) {
+        Ok(friend) => expand(body, friend),
+        Err(error) => error,
+    }
+}
+
+/// Get the parameters of `phone_a_friend_git!` from its attributes.
+fn git_params(attrs: &HashMap<String, AttrValue>) -> Result<GitQueueParams, TokenStream> {
+    let dir = optional_string_attr(attrs, "dir")?.unwrap_or_else(|| "phone-a-friend".to_string());
+    Ok(GitQueueParams { dir: dir.into() })
+}
+
+/// Ask the friends in the crate's friend directory, `phone-a-friend.toml`,
+/// each through the backend that the directory says.
+///
+/// With `[friend = "alice"]`, the questions are asked to that friend;
+/// any other attributes override the ones in the directory.
+/// A question can also name its own friend, like `PhoneAFriend(bob, "What about `y`?")`,
+/// with any of the backend macros. The attribute group may be empty (`[]`) if every question names a friend.
+///
+/// The directory has a table for each friend, with their `backend` and the attributes of its macro:
+///
+/// ```toml
+/// [friends.alice]
+/// backend = "telegram"
+/// token = "123456:ABC-DEF"
+/// chat_id = 111
+/// # Only ask during working hours, in their time zone; otherwise ask bob instead.
+/// hours = "09:00-17:00"
+/// days = ["Mon", "Tue", "Wed", "Thu", "Fri"]
+/// timezone = "Europe/Berlin"
+/// fallback = "bob"
+/// ```
+///
+/// Without a `fallback`, the friend's `default` answer is used outside their hours,
+/// and without that either, the build fails instead of waking them up.
+#[proc_macro]
+pub fn phone_a_friend(body: TokenStream) -> TokenStream {
+    let (attrs, body) = match split_attrs(body, None) {
+        Ok(result) => result,
+        Err(error) => return error,
+    };
+    if !attrs.contains_key("friend") {
+        return expand(body, None);
+    }
+    match backend_friend(None, &attrs) {
+        Ok(friend) => expand(body, friend),
+        Err(error) => error,
+    }
+}
+
+/// Get the friend to ask the questions that do not name one, from the attributes of a macro for the given backend.
+/// With a `friend` attribute, the friend's attributes in the directory are used, overridden by the macro's own.
+fn backend_friend(backend: Option<&str>, attrs: &HashMap<String, AttrValue>) -> Result<Option<Friend>, TokenStream> {
+    let name = match optional_string_attr(attrs, "friend")? {
+        Some(name) => name,
+        None => return Friend::new(backend.unwrap_or_default(), attrs).map(Some),
+    };
+    let mut entry = match friends::lookup(&name) {
+        Ok(found) => found,
+        Err(message) => {
+            return Err(quote! {
+                compile_error!(#message);
+            }
+            .into())
+        }
+    };
+    if let Some(backend) = backend.filter(|backend| *backend != entry.backend) {
+        let message = format!("the friend `{name}` is asked by {}, not by {backend}", entry.backend);
+        return Err(quote! {
+            compile_error!(#message);
+        }
+        .into());
+    }
+    for (key, value) in attrs {
+        if key != "friend" {
+            entry.attrs.insert(key.clone(), value.clone());
+        }
+    }
+    Friend::from_entry(entry).map(Some)
+}
+
+/// Replace the magic types in the body: the questions that name a friend are asked to that friend
+/// from the directory, and the others are asked to `friend`.
+fn expand(body: TokenStream, mut friend: Option<Friend>) -> TokenStream {
+    // Load the friends that the questions name, and the ones to ask instead when those are away.
+    let mut to_load: Vec<(String, Span)> = friend_names(&body).iter().map(|name| (name.to_string(), name.span())).collect();
+    if let Some(fallback) = friend.as_ref().and_then(Friend::fallback) {
+        to_load.push((fallback.to_string(), Span::call_site()));
+    }
+    let mut named: HashMap<String, Friend> = HashMap::new();
+    while let Some((name, span)) = to_load.pop() {
+        if named.contains_key(&name) {
+            continue;
+        }
+        let found = friends::lookup(&name).map_err(|message| -> TokenStream {
+            quote_spanned! {span.into() => compile_error!(#message);}.into()
+        });
+        match found.and_then(Friend::from_entry) {
+            Ok(found) => {
+                if let Some(fallback) = found.fallback() {
+                    to_load.push((fallback.to_string(), span));
+                }
+                named.insert(name, found);
+            }
+            Err(error) => return error,
+        };
+    }
+
+    // The friend is shown the whole item that the question is in, so keep the body as it was.
+    let items = body.clone();
+    let resp = match replace_magic_type(body, true, &mut |question, span, name, position| {
+        friends::ask_available(&mut named, friend.as_mut(), name, question, &item_context(&items, span), position)
+    }) {
+        Ok(result) => result,
+        Err(error) => error,
+    };
+    println!("Final output: {resp}");
+    resp
+}
+
+/// Find the names of the friends that the questions in the body ask, like `alice` in `PhoneAFriend(alice, "...")`.
+fn friend_names(body: &TokenStream) -> Vec<Ident> {
+    let mut names = vec![];
+    let mut after_magic = false;
+    for item in body.clone() {
+        match item {
+            TokenTree::Group(grp) if after_magic => {
+                if let Some(TokenTree::Ident(name)) = grp.stream().into_iter().next() {
+                    names.push(name);
+                }
+                after_magic = false;
+            }
+            TokenTree::Group(grp) => names.extend(friend_names(&grp.stream())),
+            TokenTree::Ident(ident) => after_magic = ident.to_string() == "PhoneAFriend",
+            _ => after_magic = false,
+        }
+    }
+    names
+}
+
+/// Split the macro input into the parsed attribute group and the rest of the body,
+/// or produce a compile error if there is no attribute group.
+/// If `expected` lists some required attributes, the group must not be empty either.
+fn split_attrs(
+    body: TokenStream,
+    expected: Option<&str>,
+) -> Result<(HashMap<String, AttrValue>, TokenStream), TokenStream> {
     let maybe_attr = parse_attrs::extract_attrs(body);
     if maybe_attr.is_none() {
-        return quote! {
+        return Err(quote! {
             compile_error!("first item must be an attribute group (put square brackets at the beginning of the macro invocation)");
         }
-        .into();
+        .into());
     }
 
     let (attr, body) = maybe_attr.unwrap();
 
     // Assert that there must be some attributes.
-    if attr.is_empty() {
-        return quote! {
-            compile_error!("expected attributes: `token` and `chat_id`");
+    if let (true, Some(expected)) = (attr.is_empty(), expected) {
+        let message = format!("expected attributes: {expected}");
+        return Err(quote! {
+            compile_error!(#message);
         }
-        .into();
+        .into());
     }
 
-
     let attrs = parse_attrs::parse_attrs(attr);
 
     println!("{attrs:?}");
+    Ok((attrs, body))
+}
 
-    // Assert that there must be attributes:
-    // - token: a string,
-    // - chat_id: an integer
-    let token: String;
-    let chat_id: i64;
-    match attrs.get("token") {
-        None => {
-            return quote! {
-                compile_error!("expected attribute `token`");
-            }
-            .into()
-        }
-        Some(lit) => {
-            token = match StringLit::try_from(lit) {
-                Ok(string) => string.into_value().to_string(),
-                Err(_) => return quote_spanned! {
-                    lit.span().into() => compile_error!("expected a string literal for the token");
-                }
-                .into(),
-            };
-            println!("Token: {token}");
+/// Get an attribute that must be a single literal, if it is present.
+/// The `kind` is used in the error message if it is a list instead.
+fn optional_literal_attr<'a>(
+    attrs: &'a HashMap<String, AttrValue>,
+    name: &str,
+    kind: &str,
+) -> Result<Option<&'a Literal>, TokenStream> {
+    match attrs.get(name) {
+        None => Ok(None),
+        Some(AttrValue::Literal(lit)) => Ok(Some(lit)),
+        Some(AttrValue::List(_)) => {
+            let message = format!("expected {kind} for the {name}, found a list");
+            Err(quote! {
+                compile_error!(#message);
+            }
+            .into())
+        }
+    }
+}
+
+/// Get a string attribute, if it is present.
+fn optional_string_attr(
+    attrs: &HashMap<String, AttrValue>,
+    name: &str,
+) -> Result<Option<String>, TokenStream> {
+    match optional_literal_attr(attrs, name, "a string literal")? {
+        None => Ok(None),
+        Some(lit) => string_literal(lit, name).map(Some),
+    }
+}
+
+fn string_literal(lit: &Literal, name: &str) -> Result<String, TokenStream> {
+    match StringLit::try_from(lit) {
+        Ok(string) => Ok(string.into_value().to_string()),
+        Err(_) => {
+            let message = format!("expected a string literal for the {name}");
+            Err(quote_spanned! {
+                lit.span().into() => compile_error!(#message);
+            }
+            .into())
         }
     }
+}
 
-    match attrs.get("chat_id") {
-        None => {
-            return quote! {
-                compile_error!("expected attribute `chat_id`");
+/// Get how many friends must give the same answer if the `friends` are all asked at once (`ask_friends = "all"`).
+fn fan_out_attrs(attrs: &HashMap<String, AttrValue>, friends: &[i64]) -> Result<Option<usize>, TokenStream> {
+    let consensus = optional_int_attr(attrs, "consensus")?;
+    match optional_string_attr(attrs, "ask_friends")?.as_deref() {
+        None | Some("in_turn") if consensus.is_none() => Ok(None),
+        None | Some("in_turn") => Err(quote! {
+            compile_error!("`consensus` needs `ask_friends = \"all\"`");
+        }
+        .into()),
+        Some("all") if attrs.contains_key("poll") => Err(quote! {
+            compile_error!("`poll` cannot be used with `ask_friends = \"all\"`");
+        }
+        .into()),
+        Some("all") => match consensus.unwrap_or(1) {
+            needed @ 1.. if needed <= friends.len() => Ok(Some(needed)),
+            _ => Err(quote! {
+                compile_error!("`consensus` must be between 1 and the number of `friends`");
             }
-            .into()
+            .into()),
         },
-        Some(lit) => {
-            chat_id = match lit.to_string().parse::<i64>() {
-                Ok(id) => id,
-                Err(_) => return quote_spanned! {
-                    lit.span().into() => compile_error!("expected an integer literal for the chat_id");
-                }
-                .into(),
-            };
-            println!("Chat ID: {chat_id}");
+        Some(_) => Err(quote! {
+            compile_error!("expected `ask_friends` to be \"in_turn\" or \"all\"");
+        }
+        .into()),
+    }
+}
+
+/// Get the settings for asking the Telegram question as a poll, if `poll = "on"` is given.
+fn poll_attrs(attrs: &HashMap<String, AttrValue>, choices: &[String]) -> Result<Option<PollParams>, TokenStream> {
+    if optional_string_attr(attrs, "poll")?.as_deref() != Some("on") {
+        return Ok(None);
+    }
+    // These are Telegram's limits on the number of poll options.
+    if !(2..=10).contains(&choices.len()) {
+        return Err(quote! {
+            compile_error!("a poll needs between 2 and 10 `choices`");
         }
+        .into());
+    }
+    let tie_break = match optional_string_attr(attrs, "tie_break")?.as_deref() {
+        None | Some("first") => TieBreak::First,
+        Some("random") => TieBreak::Random,
+        Some("fail") => TieBreak::Fail,
+        Some(_) => {
+            return Err(quote! {
+                compile_error!("expected `tie_break` to be \"first\", \"random\" or \"fail\"");
+            }
+            .into())
+        }
+    };
+    Ok(Some(PollParams {
+        quorum: optional_int_attr(attrs, "poll_quorum")?,
+        deadline: Duration::from_secs(optional_int_attr(attrs, "poll_deadline")?.unwrap_or(60)),
+        tie_break,
+    }))
+}
+
+/// Get a list of strings attribute, like `choices = ["u32", "u64"]`, if it is present.
+/// A single string is accepted as a list with one item.
+fn optional_string_list_attr(
+    attrs: &HashMap<String, AttrValue>,
+    name: &str,
+) -> Result<Option<Vec<String>>, TokenStream> {
+    match attrs.get(name) {
+        None => Ok(None),
+        Some(AttrValue::Literal(lit)) => Ok(Some(vec![string_literal(lit, name)?])),
+        Some(AttrValue::List(list)) => list
+            .iter()
+            .map(|lit| string_literal(lit, name))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some),
     }
+}
 
-    let mut telegram_params = TelegramParams {
-        token, chat_id, is_token_valid: false, response_timeout: Duration::from_secs(60),
+/// Get a string attribute that must be present.
+fn require_string_attr(attrs: &HashMap<String, AttrValue>, name: &str) -> Result<String, TokenStream> {
+    optional_string_attr(attrs, name)?.ok_or_else(|| missing_attr(name))
+}
+
+/// Get an integer attribute, if it is present.
+fn optional_int_attr<T: FromStr>(
+    attrs: &HashMap<String, AttrValue>,
+    name: &str,
+) -> Result<Option<T>, TokenStream> {
+    match optional_literal_attr(attrs, name, "an integer literal")? {
+        None => Ok(None),
+        Some(lit) => match lit.to_string().parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => {
+                let message = format!("expected an integer literal for the {name}");
+                Err(quote_spanned! {
+                    lit.span().into() => compile_error!(#message);
+                }
+                .into())
+            }
+        },
+    }
+}
+
+/// Get a list of integers attribute, like `friends = [111, 222]`, if it is present.
+fn optional_int_list_attr<T: FromStr>(
+    attrs: &HashMap<String, AttrValue>,
+    name: &str,
+) -> Result<Option<Vec<T>>, TokenStream> {
+    let list = match attrs.get(name) {
+        None => return Ok(None),
+        Some(AttrValue::Literal(lit)) => std::slice::from_ref(lit),
+        Some(AttrValue::List(list)) => list.as_slice(),
     };
+    list.iter()
+        .map(|lit| {
+            lit.to_string().parse::<T>().map_err(|_| {
+                let message = format!("expected integer literals in the {name}");
+                quote_spanned! {
+                    lit.span().into() => compile_error!(#message);
+                }
+                .into()
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
 
-    let resp = match replace_magic_type(body, &mut telegram_params) {
-        Ok(result) => result,
-        Err(error) => error,
+/// Get an integer attribute that must be present.
+fn require_int_attr<T: FromStr>(attrs: &HashMap<String, AttrValue>, name: &str) -> Result<T, TokenStream> {
+    optional_int_attr(attrs, name)?.ok_or_else(|| missing_attr(name))
+}
+
+/// Get the `allowed_users` attribute: a list of user IDs and usernames, like `[123, "alice"]`.
+fn allowed_users_attr(attrs: &HashMap<String, AttrValue>) -> Result<Vec<AllowedUser>, TokenStream> {
+    let list = match attrs.get("allowed_users") {
+        None => return Ok(vec![]),
+        Some(AttrValue::Literal(lit)) => vec![lit.clone()],
+        Some(AttrValue::List(list)) => list.clone(),
     };
-    println!("Final output: {resp}");
-    resp
+    list.iter()
+        .map(|lit| {
+            if let Ok(username) = StringLit::try_from(lit) {
+                Ok(AllowedUser::Username(username.into_value().to_string()))
+            } else if let Ok(id) = lit.to_string().parse() {
+                Ok(AllowedUser::Id(id))
+            } else {
+                Err(quote_spanned! {
+                    lit.span().into() => compile_error!("expected a user ID or a username string in the allowed_users");
+                }
+                .into())
+            }
+        })
+        .collect()
+}
+
+fn missing_attr(name: &str) -> TokenStream {
+    let message = format!("expected attribute `{name}`");
+    quote! {
+        compile_error!(#message);
+    }
+    .into()
+}
+
+/// Turn an error from phoning a friend into a `compile_error!` pointing at the magic type.
+fn error_to_compile_error(error: AskAFriendError, span: Span) -> TokenStream {
+    println!("Error while phoning friend: {:?}", error);
+    if let AskAFriendError::Pending(_) = error {
+        // This one is emitted in place of the magic type, so it must not end with a semicolon.
+        let message = format!("waiting for a friend: {error}");
+        return quote_spanned! {span.into() => compile_error!(#message)}.into();
+    }
+    let message = format!("failed to phone a friend: {}", error.with_sources());
+    quote_spanned! {span.into() => compile_error!(#message);}.into()
 }
 
+/// Give the tokens of an answer the span of its question, so that the compiler's errors about the answer point there.
+fn respan(answer: TokenStream, span: Span) -> TokenStream {
+    answer
+        .into_iter()
+        .map(|token| match token {
+            TokenTree::Group(group) => {
+                let mut respanned = Group::new(group.delimiter(), respan(group.stream(), span));
+                respanned.set_span(span);
+                TokenTree::Group(respanned)
+            }
+            mut token => {
+                token.set_span(span);
+                token
+            }
+        })
+        .collect()
+}
+
+/// Asks a question from the body, given where it is, the name of the friend that it asks if it names one,
+/// and whether it is in place of a type or of items.
+type Ask<'a> = dyn FnMut(&str, Span, Option<&str>, Position) -> Result<String, AskAFriendError> + 'a;
+
+/// Replace the questions in `body` with their answers.
+/// `items_allowed` is whether items can go in it, like at the top of the macro's body or in braces.
 fn replace_magic_type(
     body: TokenStream,
-    params: &mut TelegramParams,
+    items_allowed: bool,
+    ask: &mut Ask<'_>,
 ) -> Result<TokenStream, TokenStream> {
     let mut tokens: Vec<TokenStream> = vec![];
     enum ParsingState {
@@ -108,8 +774,20 @@ fn replace_magic_type(
     }
 
     let mut state = ParsingState::WaitingForIdent;
+    // An item starts at the beginning, and after the `;`, the braces or the attribute that ends the one before it;
+    // anywhere else, the question is in place of a type.
+    let mut item_starts_here = items_allowed;
+    let mut position = Position::Type;
 
     for item in body {
+        let ends_item = match &item {
+            // The answer to a question where items go is items too.
+            TokenTree::Group(_) if matches!(state, ParsingState::WaitingForGroup) => position == Position::Items,
+            TokenTree::Punct(punct) => punct.as_char() == ';',
+            TokenTree::Group(group) => matches!(group.delimiter(), Delimiter::Brace | Delimiter::Bracket),
+            _ => false,
+        };
+        let next_item_starts_here = items_allowed && ends_item;
         match item {
             // If this is a Group, then emit a new Group that has been passed through this function.
             TokenTree::Group(grp) => {
@@ -118,47 +796,38 @@ fn replace_magic_type(
                     tokens.push(
                         TokenTree::Group(Group::new(
                             grp.delimiter(),
-                            replace_magic_type(grp.stream(), params)?,
+                            replace_magic_type(grp.stream(), grp.delimiter() == Delimiter::Brace, ask)?,
                         ))
                         .into(),
                     );
                 } else {
-                    // Otherwise, it is a group that is expected to contain the phone-a-friend string.
-                    // Check that the inner token stream has only one element.
-
-                    let inner = grp.stream();
-                    if inner.is_empty() {
-                        return Err(quote_spanned! {
-                            grp.span().into() => compile_error!("expected the phone-a-friend string, found nothing");
-                        }.into());
-                    }
-                    let mut saw_one_element = false;
-                    let mut single_item = None;
-                    for grp_item in inner {
-                        if !saw_one_element {
-                            single_item = Some(grp_item);
-                            saw_one_element = true;
-                        } else {
+                    // Otherwise, it is a group that is expected to contain the phone-a-friend string,
+                    // maybe after the name of the friend to ask.
+                    let inner: Vec<TokenTree> = grp.stream().into_iter().collect();
+                    let (friend, literal) = match inner.as_slice() {
+                        [] => {
                             return Err(quote_spanned! {
-                                grp_item.span().into() => compile_error!("expected a single string literal here, found more than one item");
+                                grp.span().into() => compile_error!("expected the phone-a-friend string, found nothing");
                             }.into());
                         }
-                    }
-
-                    let single_item = single_item.unwrap(); // There is definitely something here, because earlier we checked for the empty tokenstream.
-
-                    // Check that the single item we saw is a literal
-                    let literal;
-                    if let TokenTree::Literal(lit) = single_item {
-                        literal = lit;
-                    } else {
-                        return Err(quote_spanned! {
-                            single_item.span().into() => compile_error!("expected a single string literal here");
-                        }.into());
-                    }
+                        [TokenTree::Literal(literal)] => (None, literal),
+                        [TokenTree::Ident(name), TokenTree::Punct(comma), TokenTree::Literal(literal)] if comma.as_char() == ',' => {
+                            (Some(name.to_string()), literal)
+                        }
+                        [TokenTree::Literal(_), extra, ..] => {
+                            return Err(quote_spanned! {
+                                extra.span().into() => compile_error!("expected a single string literal here, found more than one item");
+                            }.into());
+                        }
+                        [first, ..] => {
+                            return Err(quote_spanned! {
+                                first.span().into() => compile_error!("expected a string literal here, or a friend's name and a string literal");
+                            }.into());
+                        }
+                    };
 
                     let question;
-                    match StringLit::try_from(&literal) {
+                    match StringLit::try_from(literal) {
                         Ok(string) => {
                             question = string.into_value();
                         },
@@ -169,26 +838,28 @@ fn replace_magic_type(
                         }
                     };
                     
-                    let type_ident = ask_friend_via_tg(params, &question);
+                    let type_ident = ask(&question, grp.span(), friend.as_deref(), position);
                     println!("Got answer: {type_ident:?}");
                     match type_ident {
                         Ok(value) => {
-                            tokens.push(TokenTree::Ident(Ident::new(&value, grp.span())).into());
+                            let value = value.trim();
+                            let answer = check_replacement(value, Some(position)).and_then(|()| {
+                                TokenStream::from_str(value).map_err(|error| format!("`{value}` cannot be read: {error}"))
+                            });
+                            match answer {
+                                Ok(answer) => tokens.push(respan(answer, grp.span())),
+                                Err(reason) => {
+                                    return Err(error_to_compile_error(AskAFriendError::InvalidAnswer(reason), grp.span()));
+                                }
+                            }
+                            state = ParsingState::WaitingForIdent;
+                        }, Err(AskAFriendError::Pending(path)) => {
+                            // Keep going, so that the build fails with the whole list of pending questions
+                            // and they can all be answered at once.
+                            tokens.push(error_to_compile_error(AskAFriendError::Pending(path), grp.span()));
                             state = ParsingState::WaitingForIdent;
                         }, Err(error) => {
-                            println!("Error while phoning friend: {:?}", error);
-                            use crate::error::AskAFriendError::*;
-                            let how_error = match error {
-                                NetworkError(_) => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: error communicating with the network");}.into()),
-                                SendMessageError => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: error sending messages");}.into()),
-                                TokenInvalid => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: the provided token could not be used");}.into()),
-                                UnknownChatId => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: the chat_id is not known to the bot");}.into()),
-                                ChatClosed => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: the user has blocked the chat with the bot");}.into()),
-                                APIError(_) => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: some error with parsing the API response");}.into()),
-                                Timeout => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: user did not provide an answer in time");}.into()),
-                                UnknownError(_) => Err(quote_spanned! {grp.span().into() => compile_error!("failed to phone a friend: unknown error (check compiler output for details)");}.into()),
-                            };
-                            return how_error;
+                            return Err(error_to_compile_error(error, grp.span()));
                         }
                     }
                 }
@@ -202,6 +873,7 @@ fn replace_magic_type(
                 }
 
                 if ident.to_string() == "PhoneAFriend" {
+                    position = if item_starts_here { Position::Items } else { Position::Type };
                     state = ParsingState::WaitingForGroup;
                 } else {
                     tokens.push(TokenTree::Ident(ident).into());
@@ -218,10 +890,11 @@ fn replace_magic_type(
                 }
             }
         }
+        item_starts_here = next_item_starts_here;
     }
 
     let mut out = TokenStream::new();
-    out.extend(tokens.into_iter());
+    out.extend(tokens);
     println!("Emitting: {out}");
     Ok(out)
 }
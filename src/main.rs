@@ -0,0 +1,239 @@
+//! The `phone-a-friend` daemon.
+//!
+//! Cargo runs many rustc processes in parallel, and if each of them called Telegram's `getUpdates` on its own,
+//! they would get 409 Conflict errors and steal each other's updates.
+//! Instead, the proc macro sends its questions to this daemon over a Unix socket;
+//! the daemon polls the updates once for each bot and hands them to every question waiting on that bot.
+//! The proc macro starts the daemon on demand, and it exits by itself after a while without questions.
+
+mod answer;
+mod daemon;
+mod error;
+mod telegram;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedReadHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+
+use crate::daemon::Response;
+use crate::error::AskAFriendError;
+use crate::telegram::api::Connection;
+use crate::telegram::open_questions;
+use crate::telegram::webhook::{self, Webhook};
+use crate::telegram::{Polling, TelegramParams, UpdateSource};
+
+/// The daemon exits after this long without any questions.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A batch of updates for a bot, or why its updates cannot be received, after which no more batches come.
+type Batch = Arc<Result<Vec<serde_json::Value>, String>>;
+
+/// A bot, and the Bot API server that its updates come from: a self-hosted one has updates of its own.
+type PollerKey = (String, Connection);
+
+/// The update pollers that are currently running, by bot.
+type Pollers = Arc<Mutex<HashMap<PollerKey, broadcast::Sender<Batch>>>>;
+
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("daemon") => {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(run());
+        }
+        _ => {
+            eprintln!("Usage: phone-a-friend daemon");
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn run() {
+    let path = match daemon::socket_path() {
+        Ok(path) => path,
+        Err(reason) => {
+            eprintln!("Cannot listen for questions: {reason}");
+            std::process::exit(1);
+        }
+    };
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(_) if std::os::unix::net::UnixStream::connect(&path).is_ok() => {
+            // Another daemon was started at the same time, and got there first.
+            return;
+        }
+        Err(_) => {
+            // The socket was left over by a daemon that did not exit cleanly.
+            let _ = std::fs::remove_file(&path);
+            UnixListener::bind(&path).expect("cannot listen on the daemon socket")
+        }
+    };
+
+    let pollers = Pollers::default();
+    let active = Arc::new(AtomicUsize::new(0));
+    // Stopping the daemon must not leave the webhooks set: the bots could not be polled anymore.
+    let terminated = open_questions::terminated();
+    tokio::pin!(terminated);
+    loop {
+        let accepted = tokio::select! {
+            accepted = tokio::time::timeout(IDLE_TIMEOUT, listener.accept()) => accepted,
+            _ = &mut terminated => break,
+        };
+        match accepted {
+            Ok(Ok((stream, _))) => {
+                let pollers = pollers.clone();
+                let active = active.clone();
+                active.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    handle_client(stream, &pollers).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Ok(Err(error)) => eprintln!("Error accepting a connection: {error}"),
+            Err(_) if active.load(Ordering::SeqCst) == 0 => break,
+            Err(_) => {}
+        }
+    }
+    webhook::delete_all().await;
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Answer a single question from a proc macro.
+async fn handle_client(stream: UnixStream, pollers: &Pollers) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.is_err() {
+        return;
+    }
+    let response = match daemon::read_request(&line) {
+        Ok(mut request) => {
+            let mut updates = SharedUpdates { receiver: subscribe(pollers, &request.params) };
+            tokio::select! {
+                result = telegram::ask_friend_via_tg_inner(&mut request.params, &request.question, &request.item, &mut updates) => {
+                    match result {
+                        Ok(answer) => Response::Answer(answer),
+                        Err(error) => Response::Error(error.sendable()),
+                    }
+                }
+                // The build stopped waiting, like when rustc was interrupted or killed,
+                // so its questions are cancelled before they stop being waited for.
+                () = async {
+                    disconnected(&mut reader).await;
+                    open_questions::cancel_task_questions().await;
+                } => return,
+            }
+        }
+        Err(response) => response,
+    };
+    let mut line = serde_json::to_string(&response).expect("response can always be serialized");
+    line.push('\n');
+    let _ = writer.write_all(line.as_bytes()).await;
+}
+
+/// Wait until the proc macro closes the connection. It sends nothing after its request.
+async fn disconnected(reader: &mut BufReader<OwnedReadHalf>) {
+    let mut rest = String::new();
+    while let Ok(1..) = reader.read_line(&mut rest).await {
+        rest.clear();
+    }
+}
+
+/// Start receiving the updates for the given bot, starting its poller if needed.
+fn subscribe(pollers: &Pollers, params: &TelegramParams) -> broadcast::Receiver<Batch> {
+    let mut map = pollers.lock().unwrap();
+    if let Some(sender) = map.get(&poller_key(params)) {
+        return sender.subscribe();
+    }
+    let (sender, receiver) = broadcast::channel(64);
+    map.insert(poller_key(params), sender.clone());
+    tokio::spawn(poll_bot(params.clone(), sender, pollers.clone()));
+    receiver
+}
+
+/// Get the updates for one bot, by webhook if it has one or else with `getUpdates`,
+/// and broadcast them until nobody is waiting for them anymore.
+async fn poll_bot(mut params: TelegramParams, sender: broadcast::Sender<Batch>, pollers: Pollers) {
+    params.connect();
+    if params.webhook_url.is_none() {
+        relay_updates(&params, &mut Polling::new(&params), &sender, &pollers).await;
+        return;
+    }
+    match Webhook::start(&params).await {
+        Ok(mut webhook) => {
+            relay_updates(&params, &mut webhook, &sender, &pollers).await;
+            webhook.stop(&params).await;
+        }
+        Err(error) => {
+            // The daemon's output goes nowhere, so the waiting questions fail with the reason.
+            let _ = sender.send(Arc::new(Err(format!("cannot start the webhook: {}", error.with_sources()))));
+            pollers.lock().unwrap().remove(&poller_key(&params));
+        }
+    }
+}
+
+fn poller_key(params: &TelegramParams) -> PollerKey {
+    (params.token.clone(), params.connection.clone())
+}
+
+async fn relay_updates(
+    params: &TelegramParams,
+    source: &mut impl UpdateSource,
+    sender: &broadcast::Sender<Batch>,
+    pollers: &Pollers,
+) {
+    loop {
+        {
+            let mut map = pollers.lock().unwrap();
+            if sender.receiver_count() == 0 {
+                map.remove(&poller_key(params));
+                return;
+            }
+        }
+        match source.next_batch(params).await {
+            Ok(updates) => {
+                // Every waiting question sees the whole batch, so the poller consumes all of it.
+                for update_id in updates.iter().filter_map(|u| u.get("update_id")?.as_i64()) {
+                    source.consumed(update_id);
+                }
+                let _ = sender.send(Arc::new(Ok(updates)));
+            }
+            Err(error) => {
+                eprintln!("Error getting updates: {}", error.with_sources());
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// The updates for a question that the daemon is asking, as received from the bot's poller.
+struct SharedUpdates {
+    receiver: broadcast::Receiver<Batch>,
+}
+
+impl UpdateSource for SharedUpdates {
+    async fn next_batch(&mut self, _params: &TelegramParams) -> Result<Vec<serde_json::Value>, AskAFriendError> {
+        match tokio::time::timeout(Duration::from_secs(5), self.receiver.recv()).await {
+            Ok(Ok(batch)) => match &*batch {
+                Ok(updates) => Ok(updates.clone()),
+                Err(reason) => Err(AskAFriendError::DaemonError(reason.clone())),
+            },
+            // Some batches were dropped because this question was too slow to look at them;
+            // that is not a reason to stop waiting.
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => Ok(vec![]),
+            Ok(Err(broadcast::error::RecvError::Closed)) => Err(AskAFriendError::UnknownError(
+                "the update poller stopped".to_string(),
+            )),
+            Err(_) => Ok(vec![]),
+        }
+    }
+
+    fn consumed(&mut self, _update_id: i64) {
+        // The poller has already moved past the whole batch.
+    }
+}
@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::error::AskAFriendError;
+
+/// Implementation of the "ask friend" feature using a Mastodon (or compatible) server as a backend.
+///
+/// When this function is called, it will post the question as a direct-visibility status
+/// mentioning the friend's account, then poll the notifications
+/// until the friend replies to that status.
+/// The text of the reply, without HTML and mentions, is returned in the `Ok` variant.
+pub(crate) fn ask_friend_via_mastodon(
+    params: &mut MastodonParams,
+    query: &str,
+) -> Result<String, AskAFriendError> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(ask_friend_via_mastodon_inner(params, query))
+}
+
+async fn ask_friend_via_mastodon_inner(
+    params: &mut MastodonParams,
+    query: &str,
+) -> Result<String, AskAFriendError> {
+    let client = reqwest::Client::new();
+    verify_credentials(&client, params).await?;
+    let status_id = post_question(&client, params, query).await?;
+    wait_for_reply(&client, params, &status_id).await
+}
+
+pub(crate) struct MastodonParams {
+    /// Base URL of the instance the bot account lives on, like `https://mastodon.social`.
+    pub instance: String,
+    pub access_token: String,
+    /// The friend's account, like `@friend@example.social`.
+    pub account: String,
+    pub is_token_valid: bool,
+    pub poll_interval: Duration,
+    pub response_timeout: Duration,
+}
+
+impl MastodonParams {
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.instance.trim_end_matches('/'))
+    }
+
+    /// The friend's account without the leading `@`, as it appears in the `acct` field.
+    fn acct(&self) -> &str {
+        self.account.trim_start_matches('@')
+    }
+}
+
+/// Check that the access token belongs to an account on the instance.
+/// The response is stored in the MastodonParams.
+async fn verify_credentials(
+    client: &reqwest::Client,
+    params: &mut MastodonParams,
+) -> Result<(), AskAFriendError> {
+    if params.is_token_valid {
+        return Ok(());
+    }
+
+    let ok = client
+        .get(params.url("/api/v1/accounts/verify_credentials"))
+        .bearer_auth(&params.access_token)
+        .send()
+        .await
+        .map_err(AskAFriendError::NetworkError)?
+        .status()
+        .is_success();
+    if !ok {
+        Err(AskAFriendError::TokenInvalid)
+    } else {
+        params.is_token_valid = true;
+        Ok(())
+    }
+}
+
+/// Post the question as a direct status mentioning the friend, and return the status's ID.
+async fn post_question(
+    client: &reqwest::Client,
+    params: &MastodonParams,
+    query: &str,
+) -> Result<String, AskAFriendError> {
+    let res = client
+        .post(params.url("/api/v1/statuses"))
+        .bearer_auth(&params.access_token)
+        .json(&serde_json::json!({
+            "status": format!("@{} {query}", params.acct()),
+            "visibility": "direct",
+        }))
+        .send()
+        .await
+        .map_err(AskAFriendError::NetworkError)?;
+    println!("{res:?}");
+    if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(AskAFriendError::TokenInvalid);
+    } else if !res.status().is_success() {
+        // Mastodon uses 422 for statuses that fail validation, like an unknown mention or a too long text,
+        // and explains which in the `error` field.
+        let status = res.status();
+        let json: serde_json::Value = res.json().await.unwrap_or_default();
+        let description = json.get("error").and_then(|e| e.as_str()).map_or_else(|| status.to_string(), |e| e.to_string());
+        return Err(AskAFriendError::SendMessageError(description));
+    }
+
+    let json: serde_json::Value = res.json().await.map_err(AskAFriendError::APIError)?;
+    json.get("id")
+        .and_then(|id| id.as_str())
+        .map(|id| id.to_string())
+        .ok_or_else(|| AskAFriendError::UnknownError("id not found in posted status".to_string()))
+}
+
+/// Poll the mention notifications until the friend replies to the given status.
+async fn wait_for_reply(
+    client: &reqwest::Client,
+    params: &MastodonParams,
+    status_id: &str,
+) -> Result<String, AskAFriendError> {
+    // With `since_id`, more new notifications than a page would give the newest page, skipping the ones before it;
+    // with `min_id`, the page right after the last one seen is given, so none are skipped.
+    let mut min_id: Option<String> = None;
+    let waiting_period_start = Instant::now();
+    loop {
+        let mut request = client
+            .get(params.url("/api/v1/notifications"))
+            .bearer_auth(&params.access_token)
+            .query(&[("types[]", "mention"), ("limit", "40")]);
+        if let Some(min_id) = &min_id {
+            request = request.query(&[("min_id", min_id)]);
+        }
+        let res = request.send().await.map_err(AskAFriendError::NetworkError)?;
+        if !res.status().is_success() {
+            return Err(AskAFriendError::UnknownError(
+                "notifications returned non-200 status code".to_string(),
+            ));
+        }
+
+        let json: serde_json::Value = res.json().await.map_err(AskAFriendError::APIError)?;
+        let notifications = json.as_array().ok_or_else(|| {
+            AskAFriendError::UnknownError("notifications returned non-array result".to_string())
+        })?;
+
+        // Notifications are listed newest first, so look at the oldest one first.
+        for notification in notifications.iter().rev() {
+            println!("Got notification {notification}");
+            let Some(status) = notification.get("status") else {
+                continue;
+            };
+            if status.get("in_reply_to_id").and_then(|i| i.as_str()) != Some(status_id) {
+                continue;
+            }
+            let author = status
+                .get("account")
+                .and_then(|a| a.get("acct"))
+                .and_then(|a| a.as_str())
+                .unwrap_or_default();
+            if !is_same_account(author, params) {
+                continue;
+            }
+            if let Some(content) = status.get("content").and_then(|c| c.as_str()) {
+                return Ok(strip_mentions(&html_to_text(content)));
+            }
+        }
+        if let Some(newest) = notifications.first().and_then(|n| n.get("id")).and_then(|i| i.as_str()) {
+            min_id = Some(newest.to_string());
+        }
+
+        // Check if we're out of time
+        if waiting_period_start.elapsed() > params.response_timeout {
+            return Err(AskAFriendError::Timeout);
+        }
+        tokio::time::sleep(params.poll_interval).await;
+    }
+}
+
+/// The `acct` field has no domain for accounts on our own instance,
+/// so `friend` matches `@friend@our.instance` as well as `friend@our.instance`.
+fn is_same_account(author: &str, params: &MastodonParams) -> bool {
+    let friend = params.acct();
+    if author == friend {
+        return true;
+    }
+    let host = params
+        .instance
+        .trim_end_matches('/')
+        .rsplit("://")
+        .next()
+        .unwrap_or_default();
+    !author.contains('@') && friend == format!("{author}@{host}")
+}
+
+/// Turn the HTML content of a status into plain text.
+fn html_to_text(html: &str) -> String {
+    let html = html
+        .replace("<br>", "\n")
+        .replace("<br/>", "\n")
+        .replace("<br />", "\n")
+        .replace("</p><p>", "\n\n");
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Remove the `@mentions` that Mastodon puts at the start of every reply.
+fn strip_mentions(text: &str) -> String {
+    let mut rest = text.trim_start();
+    while rest.starts_with('@') {
+        rest = rest
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, after)| after)
+            .trim_start();
+    }
+    rest.trim_end().to_string()
+}
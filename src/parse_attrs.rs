@@ -2,10 +2,18 @@ use std::collections::HashMap;
 
 use proc_macro::{Literal, TokenStream};
 
+/// The value of an attribute: either a single literal,
+/// or a list of literals in square brackets, like `choices = ["u32", "u64"]`.
+#[derive(Clone, Debug)]
+pub enum AttrValue {
+    Literal(Literal),
+    List(Vec<Literal>),
+}
+
 /// This function takes in a TokenStream
 /// and returns a HashMap of the attributes
 /// that were specified using the `something = "something"` syntax.
-pub fn parse_attrs(attrs: TokenStream) -> HashMap<String, Literal> {
+pub fn parse_attrs(attrs: TokenStream) -> HashMap<String, AttrValue> {
     let mut map = HashMap::new();
     let mut current_ident = None;
     #[derive(PartialEq)]
@@ -15,6 +23,7 @@ pub fn parse_attrs(attrs: TokenStream) -> HashMap<String, Literal> {
         Value,
     }
     let mut currently_expecting = Expecting::Ident;
+    let mut negative = false;
     for token in attrs {
         match token {
             proc_macro::TokenTree::Ident(ident) => {
@@ -32,6 +41,8 @@ pub fn parse_attrs(attrs: TokenStream) -> HashMap<String, Literal> {
                     } else {
                         panic!("Unexpected equals");
                     }
+                } else if punct.as_char() == '-' && currently_expecting == Expecting::Value {
+                    negative = true;
                 } else {
                     // Otherwise, it's a comma, which we ignore
                 }
@@ -39,20 +50,52 @@ pub fn parse_attrs(attrs: TokenStream) -> HashMap<String, Literal> {
             proc_macro::TokenTree::Literal(literal) => {
                 if currently_expecting == Expecting::Value {
                     let ident = current_ident.take().unwrap();
-                    map.insert(ident.to_string(), literal);
+                    let literal = if std::mem::take(&mut negative) { negate(literal) } else { literal };
+                    map.insert(ident.to_string(), AttrValue::Literal(literal));
                     currently_expecting = Expecting::Ident;
                 } else {
                     panic!("Unexpected literal");
                 }
             }
-            proc_macro::TokenTree::Group(_group) => {
-                panic!("Unexpected group");
+            proc_macro::TokenTree::Group(group) => {
+                if currently_expecting == Expecting::Value
+                    && group.delimiter() == proc_macro::Delimiter::Bracket
+                {
+                    let ident = current_ident.take().unwrap();
+                    map.insert(ident.to_string(), AttrValue::List(parse_list(group.stream())));
+                    currently_expecting = Expecting::Ident;
+                } else {
+                    panic!("Unexpected group");
+                }
             }
         }
     }
     map
 }
 
+/// Parse the contents of a `[...]` attribute value as a comma-separated list of literals.
+fn parse_list(items: TokenStream) -> Vec<Literal> {
+    let mut list = vec![];
+    let mut negative = false;
+    for token in items {
+        match token {
+            proc_macro::TokenTree::Literal(literal) if std::mem::take(&mut negative) => list.push(negate(literal)),
+            proc_macro::TokenTree::Literal(literal) => list.push(literal),
+            proc_macro::TokenTree::Punct(punct) if punct.as_char() == '-' => negative = true,
+            proc_macro::TokenTree::Punct(punct) if punct.as_char() == ',' => {}
+            _ => panic!("Unexpected item in list"),
+        }
+    }
+    list
+}
+
+/// Negative numbers, like the IDs of group chats, are a `-` followed by a literal,
+/// so put them back together into one literal.
+fn negate(literal: Literal) -> Literal {
+    let mut negated: Literal = format!("-{literal}").parse().expect("Unexpected literal after minus");
+    negated.set_span(literal.span());
+    negated
+}
 
 /// This function takes a TokenStream that starts with a group in [square brackets],
 /// and returns two TokenStreams: one is the contents of that group, and the other is the rest of the input.
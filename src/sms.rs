@@ -0,0 +1,233 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::error::AskAFriendError;
+
+/// Characters that can be sent in the GSM 03.38 basic character set.
+/// Messages made only of these characters fit 160 per SMS segment,
+/// while anything else forces UCS-2 encoding and only fits 70.
+const GSM7_CHARS: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?\
+¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Implementation of the "ask friend" feature using a Twilio-compatible SMS API as a backend.
+///
+/// When this function is called, it will send the question to the friend's phone number
+/// (split into several SMS if needed), then poll the inbound messages from that number
+/// until a new one arrives. The text of that message is returned in the `Ok` variant.
+pub(crate) fn ask_friend_via_sms(params: &SmsParams, query: &str) -> Result<String, AskAFriendError> {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(ask_friend_via_sms_inner(params, query))
+}
+
+async fn ask_friend_via_sms_inner(params: &SmsParams, query: &str) -> Result<String, AskAFriendError> {
+    let client = reqwest::Client::new();
+
+    // Remember the replies that were already there, so that an old message is not taken as the answer.
+    let already_seen: HashSet<String> = get_inbound_messages(&client, params)
+        .await?
+        .into_iter()
+        .map(|(sid, _)| sid)
+        .collect();
+
+    for part in split_sms(query, params.max_parts) {
+        send_sms(&client, params, &part).await?;
+    }
+
+    let waiting_period_start = Instant::now();
+    loop {
+        tokio::time::sleep(params.poll_interval).await;
+
+        let messages = get_inbound_messages(&client, params).await?;
+        // The API lists the newest messages first, so the oldest new one is the first reply.
+        if let Some((_, body)) = messages
+            .into_iter()
+            .rev()
+            .find(|(sid, _)| !already_seen.contains(sid))
+        {
+            return Ok(body.trim().to_string());
+        }
+
+        // Check if we're out of time
+        if waiting_period_start.elapsed() > params.response_timeout {
+            return Err(AskAFriendError::Timeout);
+        }
+    }
+}
+
+pub(crate) struct SmsParams {
+    /// Base URL of the API, like `https://api.twilio.com`.
+    /// This can be pointed at a local stand-in for testing.
+    pub api_base: String,
+    pub account_sid: String,
+    pub auth_token: String,
+    /// The phone number that messages are sent from.
+    pub from: String,
+    /// The friend's phone number.
+    pub to: String,
+    /// The question is never split into more than this many messages;
+    /// the last one is truncated instead.
+    pub max_parts: usize,
+    pub poll_interval: Duration,
+    pub response_timeout: Duration,
+}
+
+impl SmsParams {
+    fn messages_url(&self) -> String {
+        format!(
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            self.api_base.trim_end_matches('/'),
+            self.account_sid
+        )
+    }
+}
+
+/// Send a single SMS to the friend.
+async fn send_sms(client: &reqwest::Client, params: &SmsParams, body: &str) -> Result<(), AskAFriendError> {
+    let res = client
+        .post(params.messages_url())
+        .basic_auth(&params.account_sid, Some(&params.auth_token))
+        .form(&[("From", &params.from[..]), ("To", &params.to[..]), ("Body", body)])
+        .send()
+        .await
+        .map_err(AskAFriendError::NetworkError)?;
+    println!("{res:?}");
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    } else if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(AskAFriendError::TokenInvalid);
+    }
+
+    // Twilio explains what went wrong in the `message` field.
+    let json: serde_json::Value = res.json().await.unwrap_or_default();
+    let description = json.get("message").and_then(|m| m.as_str()).map_or_else(|| status.to_string(), |m| m.to_string());
+    if status == reqwest::StatusCode::BAD_REQUEST {
+        // Twilio uses 400 for numbers that are invalid or cannot receive messages.
+        Err(AskAFriendError::UnknownChatId(description))
+    } else {
+        Err(AskAFriendError::SendMessageError(description))
+    }
+}
+
+/// Get the messages that the friend has sent to our number, newest first,
+/// as pairs of `(sid, body)`.
+async fn get_inbound_messages(
+    client: &reqwest::Client,
+    params: &SmsParams,
+) -> Result<Vec<(String, String)>, AskAFriendError> {
+    let res = client
+        .get(params.messages_url())
+        .basic_auth(&params.account_sid, Some(&params.auth_token))
+        .query(&[("From", &params.to[..]), ("To", &params.from[..]), ("PageSize", "50")])
+        .send()
+        .await
+        .map_err(AskAFriendError::NetworkError)?;
+    if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(AskAFriendError::TokenInvalid);
+    } else if !res.status().is_success() {
+        return Err(AskAFriendError::UnknownError(
+            "listing messages returned non-200 status code".to_string(),
+        ));
+    }
+
+    let json: serde_json::Value = res.json().await.map_err(AskAFriendError::APIError)?;
+    let messages = json.get("messages").and_then(|m| m.as_array()).ok_or_else(|| {
+        AskAFriendError::UnknownError("listing messages returned no `messages` array".to_string())
+    })?;
+
+    Ok(messages
+        .iter()
+        .filter(|m| {
+            m.get("direction")
+                .and_then(|d| d.as_str())
+                .is_none_or(|d| d == "inbound")
+        })
+        .filter_map(|m| {
+            let sid = m.get("sid")?.as_str()?;
+            let body = m.get("body")?.as_str()?;
+            Some((sid.to_string(), body.to_string()))
+        })
+        .collect())
+}
+
+/// Split a message into parts that each fit into a single SMS segment.
+///
+/// Messages that fit in one segment are sent as they are.
+/// Longer ones are split at whitespace where possible, and each part gets a ` (i/n)` suffix.
+/// If more than `max_parts` would be needed, the last part is cut short with `...`
+/// (not with `…`, which would force the whole message into UCS-2).
+fn split_sms(text: &str, max_parts: usize) -> Vec<String> {
+    let max_parts = max_parts.max(1);
+    let segment_len = if text.chars().all(|c| GSM7_CHARS.contains(c)) { 160 } else { 70 };
+    if text.chars().count() <= segment_len {
+        return vec![text.to_string()];
+    }
+
+    // Leave room for the " (i/n)" counter; the parts are never more than 99.
+    let part_len = segment_len - " (99/99)".len();
+    let mut parts = vec![];
+    let mut rest: Vec<char> = text.chars().collect();
+    while !rest.is_empty() {
+        if rest.len() <= part_len {
+            parts.push(rest.iter().collect::<String>());
+            break;
+        }
+        if parts.len() + 1 == max_parts {
+            // This is the last part we are allowed: truncate it.
+            let last: String = rest[..part_len - 3].iter().collect();
+            parts.push(format!("{}...", last.trim_end()));
+            break;
+        }
+        let split_at = rest[..=part_len]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .filter(|&i| i > 0)
+            .unwrap_or(part_len);
+        parts.push(rest[..split_at].iter().collect::<String>().trim_end().to_string());
+        rest = rest[split_at..].iter().skip_while(|c| c.is_whitespace()).copied().collect();
+    }
+
+    let count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| format!("{part} ({}/{count})", i + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_message_is_sent_as_is() {
+        assert_eq!(split_sms("What type is `x`?", 3), vec!["What type is `x`?"]);
+    }
+
+    #[test]
+    fn long_message_is_split_at_whitespace() {
+        let text = "word ".repeat(50);
+        let parts = split_sms(text.trim_end(), 5);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].ends_with("word (1/2)"));
+        assert!(parts[1].starts_with("word") && parts[1].ends_with(" (2/2)"));
+        assert!(parts.iter().all(|part| part.chars().count() <= 160));
+    }
+
+    #[test]
+    fn non_gsm_message_uses_shorter_segments() {
+        let parts = split_sms(&"ж ".repeat(50), 5);
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| part.chars().count() <= 70));
+    }
+
+    #[test]
+    fn too_many_parts_are_cut_short() {
+        let parts = split_sms(&"word ".repeat(200), 2);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[1].ends_with("... (2/2)"));
+        assert!(parts.iter().all(|part| part.chars().count() <= 160));
+    }
+}
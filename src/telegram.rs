@@ -1,39 +1,333 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
 use std::time::Duration;
 
+use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 
+use crate::answer::{check_replacement, extract_code, Position};
 use crate::error::AskAFriendError;
+use crate::telegram::api::{call_api, download_file, Api, ApiError, CallError, Connection};
+use crate::telegram::offset::{load_offset, save_offset};
+use crate::telegram::open_questions::{all_open, clean_up_abandoned, OpenQuestion, OpenQuestionGuard};
+use crate::telegram::poll::{send_poll_and_wait, PollParams};
+
+pub(crate) mod api;
+pub(crate) mod offset;
+pub(crate) mod open_questions;
+pub(crate) mod poll;
+// Only the daemon receives webhooks: one receiver per bot can be shared by all the rustc processes of a build.
+#[allow(dead_code)]
+pub(crate) mod webhook;
 
 /// Implementation of the "ask friend" feature using the Telegram API as a backend.
 ///
 /// When this function is called, it will attempt to connect to the Telegram API with the given parameters,
 /// then send a message to the given user and wait for a response.
 /// This response is then returned in the `Ok` variant.
-
+///
+/// The message shows the `item` that the question is about, in a code block.
+// The daemon asks with `ask_friend_via_tg_inner`, in the runtime that it already has.
+#[allow(dead_code)]
 pub(crate) fn ask_friend_via_tg(
     params: &mut TelegramParams,
     query: &str,
+    item: &str,
+    position: Position,
 ) -> Result<String, AskAFriendError> {
+    params.position = position;
+    // Cargo runs many rustc processes in parallel, and only one of them can call getUpdates for a bot at a time.
+    // So if possible, let the daemon ask the question: it owns the bot's updates for all of them.
+    #[cfg(unix)]
+    if params.use_daemon {
+        match crate::daemon::ask_via_daemon(params, query, item) {
+            Some(result) => return result,
+            None if params.webhook_url.is_some() => {
+                return Err(AskAFriendError::DaemonError(format!(
+                    "`webhook_url` needs the `{} daemon`, which cannot be reached; start it, or remove `webhook_url` to poll directly",
+                    params.daemon_bin
+                )))
+            }
+            None => println!("The phone-a-friend daemon is not available, polling Telegram directly"),
+        }
+    }
+
+    // If the build is interrupted while it waits, the friends are told that the questions do not need answers anymore.
+    open_questions::watch_for_termination();
+
     // Start a Tokio runtime and run it until the future completes.
     // This is necessary because the Telegram API is asynchronous.
     let rt = tokio::runtime::Runtime::new().unwrap();
-    rt.block_on(ask_friend_via_tg_inner(params, query))
+    params.connect();
+    rt.block_on(ask_friend_via_tg_inner(params, query, item, &mut Polling::new(params)))
 }
 
-async fn ask_friend_via_tg_inner(
+pub(crate) async fn ask_friend_via_tg_inner(
     params: &mut TelegramParams,
     query: &str,
+    item: &str,
+    updates: &mut impl UpdateSource,
 ) -> Result<String, AskAFriendError> {
+    params.connect();
     get_user_valid(params).await?;
-    let answer = send_message_and_wait(params, query).await?;
-    Ok(answer)
+    if params.friends.is_empty() {
+        return ask_chat(params, query, item, updates).await;
+    }
+    if let Some(needed) = params.fan_out {
+        return send_to_all_and_wait(params, needed, query, item, updates).await;
+    }
+
+    // Each friend gets an equal slice of the time, so that the build waits no longer than with a single one.
+    let slice = params.response_timeout / params.friends.len() as u32;
+    let mut not_answered: Vec<String> = vec![];
+    for (index, &friend) in params.friends.iter().enumerate() {
+        let friend_params = TelegramParams {
+            chat_id: friend,
+            response_timeout: slice,
+            friends: vec![],
+            passed_on_to: params.friends.get(index + 1).copied(),
+            ..params.clone()
+        };
+        let query = if not_answered.is_empty() {
+            query.to_string()
+        } else {
+            format!("{query}\n({} did not answer in time, so this was passed on to you.)", not_answered.join(", "))
+        };
+        match ask_chat(&friend_params, &query, item, updates).await {
+            Err(AskAFriendError::Timeout) => not_answered.push(chat_name(&friend_params).await),
+            result => return result,
+        }
+    }
+    Err(AskAFriendError::Timeout)
+}
+
+/// Ask the question in the chat of the given parameters, and wait for the answer.
+async fn ask_chat(
+    params: &TelegramParams,
+    query: &str,
+    item: &str,
+    updates: &mut impl UpdateSource,
+) -> Result<String, AskAFriendError> {
+    match &params.poll {
+        Some(poll) => send_poll_and_wait(params, poll, query, item, updates).await,
+        None => send_message_and_wait(params, query, item, updates).await,
+    }
+}
+
+/// Get a name for the chat that a friend would recognize, like their username.
+/// If the chat cannot be looked up, this is its ID.
+async fn chat_name(params: &TelegramParams) -> String {
+    let chat = call_api(
+        &params.api(),
+        "getChat",
+        &serde_json::json!({ "chat_id": params.chat_id }),
+    )
+    .await;
+    let chat = match chat {
+        Ok(chat) => chat,
+        Err(_) => return params.chat_id.to_string(),
+    };
+    if let Some(username) = chat.get("username").and_then(|u| u.as_str()) {
+        return format!("@{username}");
+    }
+    ["first_name", "title"]
+        .iter()
+        .find_map(|field| chat.get(field).and_then(|n| n.as_str()))
+        .map_or_else(|| params.chat_id.to_string(), str::to_string)
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct TelegramParams {
     pub token: String,
+    /// Where the Bot API is, and how to get to it.
+    pub connection: Connection,
+    /// The client for the Bot API, made once for a question by [`TelegramParams::connect`].
+    #[serde(skip)]
+    pub api: Option<Api>,
     pub chat_id: i64,
+    /// If not empty, the chats of friends to ask in turn, each one after the one before did not answer in time.
+    pub friends: Vec<i64>,
+    /// If set, all the `friends` are asked at once instead, and this many of them must give the same answer.
+    pub fan_out: Option<usize>,
     pub is_token_valid: bool,
     pub response_timeout: Duration,
+    /// If set, the friend is reminded of an unanswered question this often,
+    /// and warned shortly before the question times out.
+    pub remind_every: Option<Duration>,
+    /// If set, the chat of the friend that the question is passed on to when it times out, instead of failing the build.
+    pub passed_on_to: Option<i64>,
+    /// After the first answer, edits to it (or another reply) are taken instead for this long.
+    pub edit_grace: Duration,
+    /// Whether to ask through the `phone-a-friend daemon`, starting it if needed.
+    pub use_daemon: bool,
+    /// The daemon executable to start if it is not running yet.
+    pub daemon_bin: String,
+    /// If set, updates are pushed to this public URL by `setWebhook` instead of being polled.
+    pub webhook_url: Option<String>,
+    /// The local address that the webhook receiver listens on; `webhook_url` must forward to it.
+    pub webhook_listen: String,
+    /// In a group chat, only replies from these users are accepted. If empty, anyone may answer.
+    pub allowed_users: Vec<AllowedUser>,
+    /// If not empty, the answer must be one of these.
+    pub choices: Vec<String>,
+    /// If set, the friends vote on the `choices` in a poll, instead of replying with an answer.
+    pub poll: Option<PollParams>,
+    /// The answer to use when the friend replies `/skip` or `/default`.
+    pub default: Option<String>,
+    /// In a forum supergroup, all messages are sent into this topic.
+    pub message_thread_id: Option<i64>,
+    /// The crate being built, as told by Cargo, so that the friend knows who is asking.
+    pub crate_name: String,
+    pub crate_version: String,
+    /// If set, the questions from one build are sent as replies to a header message for that build.
+    /// This tells apart two builds of the same crate version: it is the process ID of the Cargo doing the build,
+    /// so the daemon sends one header for all of a build's questions, even when they come from several rustc processes.
+    pub build_thread: Option<u32>,
+    /// Whether the question being asked is in place of a type or of items, which decides what its answer can be.
+    pub position: Position,
+}
+
+/// A chat, crate name and build ID, identifying where the header message of a build was sent.
+type BuildKey = (i64, String, u32);
+
+/// The header messages that have been sent, by chat and build,
+/// so that every question of a build replies to the same one.
+static BUILD_HEADERS: Mutex<Option<HashMap<BuildKey, i64>>> = Mutex::new(None);
+
+/// How long before the question times out the friend is warned that the build will fail,
+/// or that the question will be passed on.
+const FINAL_WARNING: Duration = Duration::from_secs(30);
+
+/// The commands that a friend can reply with instead of an answer, registered with `setMyCommands`.
+const COMMANDS: &[(&str, &str)] = &[
+    ("skip", "Use the default answer for this question"),
+    ("default", "Use the default answer for this question"),
+    ("cancel", "Stop the build that is asking"),
+    ("pending", "List the questions that are waiting for an answer"),
+    ("help", "Explain how to answer"),
+];
+
+/// Only documents with these extensions are read as answers.
+const DOCUMENT_EXTENSIONS: &[&str] = &["rs", "txt"];
+
+/// Documents that are bigger than this are not downloaded; an answer is a type or a few items, after all.
+const MAX_DOCUMENT_BYTES: u64 = 64 * 1024;
+
+const HELP: &str = "Reply to a question with a type, like u32 or Vec<String>.
+It can also be in a ```rust code block, or in a .rs or .txt file.
+
+Or reply with one of these commands:
+/skip or /default - use the default answer, if the question has one
+/cancel - stop the build that is asking
+/pending - list the questions that are waiting for an answer
+/help - show this message";
+
+/// A friend who may answer in a group chat, given by their numeric user ID or by their username.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum AllowedUser {
+    Id(i64),
+    Username(String),
+}
+
+impl TelegramParams {
+    /// Make the client for the Bot API, so that all the calls for the question share it and its connections.
+    pub(crate) fn connect(&mut self) {
+        if self.api.is_none() {
+            self.api = Some(Api::new(&self.token, &self.connection));
+        }
+    }
+
+    /// The bot's way to the Bot API: the one made by [`TelegramParams::connect`], or else a new one.
+    pub(crate) fn api(&self) -> Cow<'_, Api> {
+        match &self.api {
+            Some(api) => Cow::Borrowed(api),
+            None => Cow::Owned(Api::new(&self.token, &self.connection)),
+        }
+    }
+
+    /// Check whether the sender of a message (its `from` field) may answer the question.
+    fn is_allowed(&self, from: Option<&serde_json::Value>) -> bool {
+        if self.allowed_users.is_empty() {
+            return true;
+        }
+        let id = from.and_then(|f| f.get("id")).and_then(|i| i.as_i64());
+        let username = from.and_then(|f| f.get("username")).and_then(|u| u.as_str());
+        self.allowed_users.iter().any(|user| match user {
+            AllowedUser::Id(allowed) => id == Some(*allowed),
+            AllowedUser::Username(allowed) => {
+                username.is_some_and(|u| u.eq_ignore_ascii_case(allowed.trim_start_matches('@')))
+            }
+        })
+    }
+}
+
+/// Where the updates from the bot come from while waiting for an answer.
+pub(crate) trait UpdateSource {
+    /// Wait for the next batch of updates. This may be empty if nothing happened for a few seconds.
+    async fn next_batch(&mut self, params: &TelegramParams) -> Result<Vec<serde_json::Value>, AskAFriendError>;
+
+    /// Called for each update once it has been looked at, in order,
+    /// so that it is not given to the next question again.
+    fn consumed(&mut self, update_id: i64);
+}
+
+/// Get the updates by calling `getUpdates` directly.
+pub(crate) struct Polling {
+    api: Api,
+    offset: i64,
+}
+
+impl Polling {
+    /// Start polling where the last question for this bot left off.
+    pub(crate) fn new(params: &TelegramParams) -> Self {
+        Polling { api: params.api().into_owned(), offset: load_offset(&params.token) }
+    }
+}
+
+impl UpdateSource for Polling {
+    async fn next_batch(&mut self, _params: &TelegramParams) -> Result<Vec<serde_json::Value>, AskAFriendError> {
+        get_updates(&self.api, self.offset).await
+    }
+
+    fn consumed(&mut self, update_id: i64) {
+        if update_id >= self.offset {
+            self.offset = update_id + 1;
+            save_offset(&self.api.token, self.offset);
+        }
+    }
+}
+
+/// Call `getUpdates` once, with a short long-polling timeout.
+///
+/// Telegram considers all the updates before `offset` confirmed, and does not return them again.
+/// So the offset should only be moved past updates that have actually been consumed:
+/// the rest of a batch after the answer to a question may hold the answer to the next one.
+pub(crate) async fn get_updates(api: &Api, offset: i64) -> Result<Vec<serde_json::Value>, AskAFriendError> {
+    let result = call_api(
+        api,
+        "getUpdates",
+        &serde_json::json!({
+            "offset": offset,
+            "timeout": 5,
+        }),
+    )
+    .await
+    .map_err(|e| match e {
+        CallError::Api(error) => AskAFriendError::UnknownError(format!(
+            "getUpdates returned error {}: {}",
+            error.error_code, error.description
+        )),
+        CallError::Other(error) => error,
+    })?;
+    let updates = result.as_array();
+    if updates.is_none() {
+        return Err(AskAFriendError::UnknownError(
+            "getUpdates returned non-array result".to_string(),
+        ));
+    }
+    Ok(updates.unwrap().clone())
 }
 
 /// Check whether the bot's corresponding user exists.
@@ -44,136 +338,572 @@ async fn get_user_valid(params: &mut TelegramParams) -> Result<(), AskAFriendErr
         return Ok(());
     }
 
-    let ok = reqwest::get(format!(
-        "https://api.telegram.org/bot{}/getMe",
-        params.token
-    ))
-    .await
-    .map_err(|e| AskAFriendError::NetworkError(e))?
-    .status()
-    .is_success();
-    if !ok {
-        return Err(AskAFriendError::TokenInvalid);
+    match call_api(&params.api(), "getMe", &serde_json::json!({})).await {
+        Ok(_) => {
+            params.is_token_valid = true;
+            register_commands(params).await;
+            clean_up_abandoned(&params.api()).await;
+            Ok(())
+        }
+        Err(CallError::Api(_)) => Err(AskAFriendError::TokenInvalid),
+        Err(CallError::Other(error)) => Err(error),
+    }
+}
+
+/// Register the commands that friends can reply with, so that Telegram suggests them.
+async fn register_commands(params: &TelegramParams) {
+    let commands: Vec<_> = COMMANDS
+        .iter()
+        .map(|(command, description)| serde_json::json!({ "command": command, "description": description }))
+        .collect();
+    let api = params.api();
+    let body = serde_json::json!({ "commands": commands });
+    best_effort("register the bot's commands", call_api(&api, "setMyCommands", &body)).await;
+}
+
+/// Send something that is only a courtesy to the friends, like a reminder or a reaction to their answer.
+/// The question does not depend on it, so it does not fail if this does: the failure is only logged,
+/// as "Could not {what}: ...".
+pub(crate) async fn best_effort<T, E: Into<CallError>>(what: &str, sent: impl Future<Output = Result<T, E>>) {
+    if let Err(error) = sent.await {
+        println!("Could not {what}: {}", send_error(error.into()));
+    }
+}
+
+/// Get the command that a message starts with, without its slash and without the bot's username,
+/// which Telegram adds in group chats (like `/skip@my_bot`).
+fn command(text: &str) -> Option<&str> {
+    let word = text.strip_prefix('/')?.split_whitespace().next()?;
+    Some(word.split('@').next().unwrap_or(word))
+}
+
+/// List the open questions in the chat, for `/pending`.
+fn pending_questions(params: &TelegramParams) -> String {
+    let mut list = String::from("Questions waiting for an answer:");
+    for question in all_open(&params.token).iter().filter(|q| q.chat_id == params.chat_id) {
+        list += &format!("\n- {} {}: {}", question.crate_name, question.crate_version, question.query);
+    }
+    list
+}
+
+/// Turn the failure of a `sendMessage` call into the corresponding error.
+///
+/// Telegram uses the same few status codes for very different problems,
+/// so the `description` is what tells them apart, like "Bad Request: chat not found"
+/// or "Forbidden: bot was kicked from the group chat".
+fn send_error(error: CallError) -> AskAFriendError {
+    let ApiError { error_code, description } = match error {
+        CallError::Api(error) => error,
+        CallError::Other(error) => return error,
+    };
+    let lowercase = description.to_lowercase();
+    if error_code == 401 {
+        AskAFriendError::TokenInvalid
+    } else if lowercase.contains("message is too long") {
+        AskAFriendError::MessageTooLong(description)
+    } else if lowercase.contains("can't parse entities") {
+        AskAFriendError::CantParseEntities(description)
+    } else if lowercase.contains("bot was kicked") || lowercase.contains("bot is not a member") {
+        AskAFriendError::BotKicked(description)
+    } else if lowercase.contains("bot was blocked by the user") || lowercase.contains("user is deactivated") {
+        AskAFriendError::ChatClosed(description)
+    } else if lowercase.contains("chat not found") || lowercase.contains("peer_id_invalid") {
+        AskAFriendError::UnknownChatId(description)
     } else {
-        params.is_token_valid = true;
-        return Ok(());
+        AskAFriendError::SendMessageError(description)
     }
 }
 
-/// Send a message to the given chat, without waiting for a response.
-/// This is used to send informational messages.
-#[allow(dead_code)]
-async fn send_message(params: &TelegramParams, message: &str) -> Result<(), AskAFriendError> {
-    let client = reqwest::Client::new();
-    let res = client
-        .post(format!(
-            "https://api.telegram.org/bot{}/sendMessage",
-            params.token
-        ))
-        .json(&serde_json::json!({
-            "chat_id": params.chat_id,
-            "text": message,
-        }))
-        .send()
+/// The parameters for `sendMessage` that every message to the chat has:
+/// the chat, the forum topic if there is one, and the text.
+fn message_body(params: &TelegramParams, message: &str) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "chat_id": params.chat_id,
+        "text": message,
+    });
+    if let Some(message_thread_id) = params.message_thread_id {
+        body["message_thread_id"] = message_thread_id.into();
+    }
+    body
+}
+
+/// Send a message with the given `sendMessage` parameters, and return its message ID.
+async fn send_body(params: &TelegramParams, body: &serde_json::Value) -> Result<i64, AskAFriendError> {
+    let result = call_api(&params.api(), "sendMessage", body)
         .await
-        .map_err(|e| AskAFriendError::NetworkError(e))?;
-    if res.status() == reqwest::StatusCode::BAD_REQUEST {
-        return Err(AskAFriendError::UnknownChatId);
-    } else if res.status() == reqwest::StatusCode::FORBIDDEN {
-        return Err(AskAFriendError::ChatClosed);
-    } else if !res.status().is_success() {
-        return Err(AskAFriendError::SendMessageError);
-    } else {
-        return Ok(());
+        .map_err(send_error)?;
+    result.get("message_id").and_then(|m| m.as_i64()).ok_or_else(|| {
+        AskAFriendError::UnknownError("message_id not found in successful response".to_string())
+    })
+}
+
+/// Send a message to the given chat, without waiting for a response,
+/// and return its message ID. This is used to send informational messages.
+async fn send_message(params: &TelegramParams, message: &str) -> Result<i64, AskAFriendError> {
+    send_body(params, &message_body(params, message)).await
+}
+
+/// Reply to a message in the given chat, without waiting for a response.
+async fn send_reply(params: &TelegramParams, reply_to: i64, message: &str) -> Result<(), AskAFriendError> {
+    let mut body = message_body(params, message);
+    body["reply_parameters"] = serde_json::json!({ "message_id": reply_to });
+    send_body(params, &body).await?;
+    Ok(())
+}
+
+/// Reply to a rejected answer with the reason it was rejected, using the `force_reply` layout,
+/// and return the message ID of the reply, to which the friend can answer again.
+async fn ask_again(params: &TelegramParams, reply_to: i64, reason: &str) -> Result<i64, AskAFriendError> {
+    let wanted = match params.position {
+        Position::Type => "a type",
+        Position::Items => "whole items",
+    };
+    let mut body = message_body(params, &format!("Sorry, {reason}. Please reply with {wanted}."));
+    body["reply_parameters"] = serde_json::json!({ "message_id": reply_to });
+    body["reply_markup"] = serde_json::json!({ "force_reply": true });
+    send_body(params, &body).await
+}
+
+/// Let the friend know that their answer was used: mark the question as answered, and react to the answer.
+async fn confirm_answer(params: &TelegramParams, question_id: i64, question: &str, answer: &str, answer_id: i64) {
+    let api = params.api();
+    let edit = serde_json::json!({
+        "chat_id": params.chat_id,
+        "message_id": question_id,
+        "text": format!("{question}\n\nanswered: <code>{}</code> \u{2713}", escape_html(answer)),
+        "parse_mode": "HTML",
+    });
+    best_effort("mark the question as answered", call_api(&api, "editMessageText", &edit)).await;
+
+    let reaction = serde_json::json!({
+        "chat_id": params.chat_id,
+        "message_id": answer_id,
+        "reaction": [{ "type": "emoji", "emoji": "\u{1f44d}" }],
+    });
+    best_effort("react to the answer", call_api(&api, "setMessageReaction", &reaction)).await;
+}
+
+/// If the questions of this build are collected in a thread, get the header message that starts it,
+/// sending it first if this is the build's first question.
+async fn build_thread_header(params: &TelegramParams) -> Result<Option<i64>, AskAFriendError> {
+    let Some(build_id) = params.build_thread else {
+        return Ok(None);
+    };
+    let key = (params.chat_id, params.crate_name.clone(), build_id);
+    if let Some(header) = BUILD_HEADERS.lock().unwrap().get_or_insert_with(HashMap::new).get(&key) {
+        return Ok(Some(*header));
+    }
+
+    let text = format!("Build of {} {} needs help", params.crate_name, params.crate_version);
+    let header = send_message(params, &text).await?;
+    BUILD_HEADERS.lock().unwrap().get_or_insert_with(HashMap::new).insert(key, header);
+    Ok(Some(header))
+}
+
+/// Write the question as an HTML message, below the item it is about and the crate that is asking.
+fn question_message(params: &TelegramParams, query: &str, item: &str) -> String {
+    let mut message = format!(
+        "<b>{} {}</b> asks:\n",
+        escape_html(&params.crate_name),
+        escape_html(&params.crate_version)
+    );
+    if !item.is_empty() {
+        message += &format!("<pre><code class=\"language-rust\">{}</code></pre>\n", escape_html(item));
+    }
+    message += &escape_html(query);
+    if !params.choices.is_empty() {
+        message += &format!("\nChoices: {}", escape_html(&params.choices.join(", ")));
     }
+    message
+}
+
+/// Escape the characters that are special in Telegram's HTML formatting.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
 }
 
-/// Send a message to the given chat, using the `force_reply` layout, and wait for a response.
+/// Send an HTML message to the given chat, using the `force_reply` layout, and wait for a response.
 /// This is used to send questions and wait for answers.
 async fn send_message_and_wait(
     params: &TelegramParams,
-    message: &str,
+    query: &str,
+    item: &str,
+    updates: &mut impl UpdateSource,
 ) -> Result<String, AskAFriendError> {
-    let client = reqwest::Client::new();
-    let res = client
-        .post(format!(
-            "https://api.telegram.org/bot{}/sendMessage",
-            params.token
-        ))
-        .json(&serde_json::json!({
-            "chat_id": params.chat_id,
-            "text": message,
-            "reply_markup": {
-                "force_reply": true,
-            }
-        }))
-        .send()
-        .await
-        .map_err(|e| AskAFriendError::NetworkError(e))?;
-        println!("{res:?}");
-    if res.status() == reqwest::StatusCode::BAD_REQUEST {
-        return Err(AskAFriendError::UnknownChatId);
-    } else if res.status() == reqwest::StatusCode::FORBIDDEN {
-        return Err(AskAFriendError::ChatClosed);
-    } else if !(res.status().is_success()) {
-        return Err(AskAFriendError::SendMessageError);
+    let mut sent = SentQuestion::send(params.clone(), query, item).await?;
+    let waiting_period_start = Instant::now();
+    // The answer so far, with the ID of its message, and when the friend stops being able to change it.
+    let mut answered: Option<(String, i64, Instant)> = None;
+    loop {
+        for update in updates.next_batch(params).await? {
+            // Once the answer is settled, the rest of the batch is left for the questions after this one.
+            if answered.as_ref().is_some_and(|(.., until)| Instant::now() >= *until) {
+                break;
+            }
+            consume(updates, &update);
+            let Some(message) = update_message(&update) else {
+                continue;
+            };
+            if let Some((answer, reply_id)) = sent.handle_message(message).await? {
+                // A correction replaces the answer, but does not make the grace period any longer.
+                let until = answered.map_or_else(|| Instant::now() + params.edit_grace, |(.., until)| until);
+                answered = Some((answer, reply_id, until));
+            }
+        }
+        if let Some((answer, reply_id, until)) = &answered {
+            if Instant::now() >= *until {
+                sent.confirm(answer, *reply_id).await;
+                return Ok(answer.clone());
+            }
+            continue;
+        }
+
+        // Check if we're out of time
+        if waiting_period_start.elapsed() > params.response_timeout {
+            // The friend is not left with a question that nobody waits for the answer to anymore.
+            if let Some(next) = params.passed_on_to {
+                let next = chat_name(&TelegramParams { chat_id: next, ..params.clone() }).await;
+                sent.close(&format!("This question is closed: nobody answered in time, so it was passed on to {next}.")).await;
+            }
+            return Err(AskAFriendError::Timeout);
+        }
+        sent.remind(waiting_period_start).await;
     }
+}
 
-    let json: serde_json::Value = res.json().await.map_err(|e| AskAFriendError::APIError(e))?;
-    let message_id = json.get("result").and_then(|r| r.get("message_id")).and_then(|m| m.as_i64());
-    if message_id.is_none() {
-        return Err(AskAFriendError::UnknownError("message_id not found in successful response".to_string()));
+/// Send the question to all the `friends` at once, and wait until `needed` of them give the same answer.
+///
+/// When the question is settled, either way, each friend is told so.
+async fn send_to_all_and_wait(
+    params: &TelegramParams,
+    needed: usize,
+    query: &str,
+    item: &str,
+    updates: &mut impl UpdateSource,
+) -> Result<String, AskAFriendError> {
+    let mut sent = vec![];
+    for &friend in &params.friends {
+        let friend_params = TelegramParams { chat_id: friend, friends: vec![], ..params.clone() };
+        match SentQuestion::send(friend_params, query, item).await {
+            Ok(question) => sent.push(question),
+            Err(error) => {
+                let outcome = Err(error);
+                close_all(&sent, &[], &outcome).await;
+                return outcome;
+            }
+        }
     }
 
-    let mut last_update_id = 0;
+    // The latest valid answer from each friend, by their index in `sent`, with the message ID of the answer.
+    let mut answers: Vec<(usize, String, i64)> = vec![];
+    let outcome = wait_for_agreement(params, needed, &mut sent, &mut answers, updates).await;
+    close_all(&sent, &answers, &outcome).await;
+    outcome
+}
+
+/// Wait until `needed` of the friends that the question was sent to give the same answer,
+/// or until that cannot happen anymore.
+async fn wait_for_agreement(
+    params: &TelegramParams,
+    needed: usize,
+    sent: &mut [SentQuestion],
+    answers: &mut Vec<(usize, String, i64)>,
+    updates: &mut impl UpdateSource,
+) -> Result<String, AskAFriendError> {
     let waiting_period_start = Instant::now();
+    // Once enough friends agree, they can still fix their answers until this time, like a single friend can.
+    let mut agreed_until: Option<Instant> = None;
     loop {
-        let res = client
-            .get(format!(
-                "https://api.telegram.org/bot{}/getUpdates",
-                params.token
-            ))
-            .query(&[("offset", last_update_id + 1), ("timeout", 5)])
-            .send()
+        for update in updates.next_batch(params).await? {
+            // Once the answer is settled, the rest of the batch is left for the questions after this one.
+            if agreed_until.is_some_and(|until| Instant::now() >= until) {
+                break;
+            }
+            consume(updates, &update);
+            let Some(message) = update_message(&update) else {
+                continue;
+            };
+            for (index, question) in sent.iter_mut().enumerate() {
+                if let Some((answer, reply_id)) = question.handle_message(message).await? {
+                    // A friend may change their mind, and only their last answer counts.
+                    answers.retain(|(other, ..)| *other != index);
+                    answers.push((index, answer, reply_id));
+                }
+            }
+            // A correction that takes the agreement away also ends its grace period: the next one gets its own.
+            agreed_until = match agreed_answer(answers, needed) {
+                Some(_) => agreed_until.or_else(|| Some(Instant::now() + params.edit_grace)),
+                None => None,
+            };
+        }
+
+        if let Some(answer) = agreed_answer(answers, needed) {
+            if agreed_until.is_some_and(|until| Instant::now() >= until) {
+                return Ok(answer);
+            }
+            continue;
+        }
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, answer, _) in answers.iter() {
+            *counts.entry(answer.as_str()).or_default() += 1;
+        }
+        // Stop waiting when even the friends who have not answered yet cannot make any answer reach `needed`.
+        let most = counts.values().copied().max().unwrap_or_default();
+        let cannot_agree = most + (sent.len() - answers.len()) < needed;
+        if cannot_agree || waiting_period_start.elapsed() > params.response_timeout {
+            if answers.is_empty() {
+                return Err(AskAFriendError::Timeout);
+            }
+            let mut proposed = vec![];
+            for (index, answer, _) in answers.iter() {
+                proposed.push(format!("{} answered {answer}", chat_name(&sent[*index].params).await));
+            }
+            return Err(AskAFriendError::Disagreement(proposed));
+        }
+        for (index, question) in sent.iter_mut().enumerate() {
+            // The friends who have answered are not waiting for anything.
+            if !answers.iter().any(|(other, ..)| *other == index) {
+                question.remind(waiting_period_start).await;
+            }
+        }
+    }
+}
+
+/// The answer that `needed` friends agreed on first, if any.
+/// The answers are in the order that they were given, so the first one to have enough friends behind it is used.
+fn agreed_answer(answers: &[(usize, String, i64)], needed: usize) -> Option<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    answers.iter().find_map(|(_, answer, _)| {
+        let count = counts.entry(answer.as_str()).or_default();
+        *count += 1;
+        (*count >= needed).then(|| answer.clone())
+    })
+}
+
+/// Tell each friend how the question was settled, so that none of them is left waiting on it,
+/// whether it was answered or not.
+async fn close_all(sent: &[SentQuestion], answers: &[(usize, String, i64)], outcome: &Result<String, AskAFriendError>) {
+    for (index, question) in sent.iter().enumerate() {
+        let answer = answers.iter().find(|(other, ..)| *other == index);
+        match (outcome, answer) {
+            (Ok(used), Some((_, answer, reply_id))) if used == answer => question.confirm(used, *reply_id).await,
+            (Ok(used), _) => question.close(&format!("This question is closed: {used} was used.")).await,
+            (Err(AskAFriendError::Disagreement(_)), _) => {
+                question.close("This question is closed: the friends did not agree on an answer.").await
+            }
+            (Err(_), _) => question.close("This question is closed without an answer.").await,
+        }
+    }
+}
+
+/// Take a handled update off the bot's queue. See [`get_updates`] for why the others are left on it.
+pub(crate) fn consume(updates: &mut impl UpdateSource, update: &serde_json::Value) {
+    println!("Got update {update}");
+    if let Some(update_id) = update.get("update_id").and_then(|u| u.as_i64()) {
+        updates.consumed(update_id);
+    }
+}
+
+/// The message in an update. An edited message comes with its new text, and takes the place of the old one.
+fn update_message(update: &serde_json::Value) -> Option<&serde_json::Map<String, serde_json::Value>> {
+    update.get("message").or_else(|| update.get("edited_message"))?.as_object()
+}
+
+/// A question that has been sent to a chat, and is waiting for its answer.
+struct SentQuestion {
+    /// The parameters for the chat that the question was sent to.
+    params: TelegramParams,
+    /// The question's message, in HTML.
+    question: String,
+    question_id: i64,
+    /// An answer is a reply to the question, or to a message that asked again after an answer was rejected.
+    asked_in: Vec<i64>,
+    /// Message IDs are only unique within a chat, and an old reply may still be waiting in the updates,
+    /// so a reply must be in the same chat, and sent after the question.
+    sent_at: i64,
+    next_reminder: Option<Instant>,
+    _open: OpenQuestionGuard,
+}
+
+impl SentQuestion {
+    async fn send(params: TelegramParams, query: &str, item: &str) -> Result<Self, AskAFriendError> {
+        let question = question_message(&params, query, item);
+        let mut body = message_body(&params, &question);
+        body["parse_mode"] = "HTML".into();
+        body["reply_markup"] = serde_json::json!({ "force_reply": true });
+        if let Some(header) = build_thread_header(&params).await? {
+            body["reply_parameters"] = serde_json::json!({ "message_id": header });
+        }
+        let result = call_api(&params.api(), "sendMessage", &body)
             .await
-            .map_err(|e| AskAFriendError::NetworkError(e))?;
-        if !res.status().is_success() {
-            return Err(AskAFriendError::UnknownError(
-                "getUpdates returned non-200 status code".to_string(),
-            ));
-        }
-
-        let json: serde_json::Value = res.json().await.map_err(|e| AskAFriendError::APIError(e))?;
-        let updates = json.get("result").and_then(|r| r.as_array());
-        if updates.is_none() {
-            return Err(AskAFriendError::UnknownError(
-                "getUpdates returned non-array result".to_string(),
-            ));
-        }
-        let updates = updates.unwrap();
-
-        // Find the update that is a reply to the message we sent
-        for update in updates {
-            println!("Got update {update}");
-            if let Some(update_id) = update.get("update_id").and_then(|u| u.as_i64()) {
-                last_update_id = last_update_id.max(update_id);
-            }
-            if let Some(message) = update.get("message").and_then(|m| m.as_object()) {
-                if let Some(reply_to_message) = message.get("reply_to_message").and_then(|r| r.as_object()) {
-                    if let Some(reply_to_message_id) = reply_to_message.get("message_id").and_then(|m| m.as_i64()) {
-                        if reply_to_message_id == message_id.unwrap() {
-                            if let Some(text) = message.get("text").and_then(|t| t.as_str()) {
-                                return Ok(text.to_string());
-                            }
-                        }
-                    }
+            .map_err(send_error)?;
+        println!("{result:?}");
+
+        let Some(question_id) = result.get("message_id").and_then(|m| m.as_i64()) else {
+            return Err(AskAFriendError::UnknownError("message_id not found in successful response".to_string()));
+        };
+        let open = open_questions::open(
+            OpenQuestion {
+                token: params.token.clone(),
+                connection: params.connection.clone(),
+                chat_id: params.chat_id,
+                message_id: question_id,
+                crate_name: params.crate_name.clone(),
+                crate_version: params.crate_version.clone(),
+                query: query.to_string(),
+                question: question.clone(),
+                poll_message_id: None,
+                expires_at: 0,
+                task: None,
+            },
+            params.response_timeout + params.edit_grace,
+        );
+        Ok(SentQuestion {
+            question,
+            question_id,
+            asked_in: vec![question_id],
+            sent_at: result.get("date").and_then(|d| d.as_i64()).unwrap_or_default(),
+            next_reminder: params.remind_every.map(|remind_every| Instant::now() + remind_every),
+            _open: open,
+            params,
+        })
+    }
+
+    /// Deal with a message that may be a reply to this question: the commands are carried out,
+    /// and an answer that cannot be used is rejected with the reason.
+    ///
+    /// Returns a valid answer, with the ID of the message it was in.
+    async fn handle_message(
+        &mut self,
+        message: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Option<(String, i64)>, AskAFriendError> {
+        let params = &self.params;
+        let chat_id = message.get("chat").and_then(|c| c.get("id")).and_then(|i| i.as_i64());
+        let date = message.get("date").and_then(|d| d.as_i64()).unwrap_or_default();
+        if chat_id != Some(params.chat_id) || date < self.sent_at {
+            return Ok(None);
+        }
+        let reply_to_message_id = message
+            .get("reply_to_message")
+            .and_then(|r| r.get("message_id"))
+            .and_then(|m| m.as_i64());
+        if !reply_to_message_id.is_some_and(|id| self.asked_in.contains(&id)) {
+            return Ok(None);
+        }
+        let Some(reply_id) = message.get("message_id").and_then(|m| m.as_i64()) else {
+            return Ok(None);
+        };
+
+        // In a group chat, anybody can reply, but only the friends on the list may answer.
+        if !params.is_allowed(message.get("from")) {
+            let turned_down = send_reply(params, reply_id, "Sorry, you are not authorised to answer this question.");
+            best_effort("turn down an answer from somebody who may not answer", turned_down).await;
+            return Ok(None);
+        }
+        if let Some(document) = message.get("document") {
+            return match document_text(params, document).await {
+                Ok(text) => self.check_answer(&text, reply_id).await,
+                Err(reason) => {
+                    self.asked_in.push(ask_again(params, reply_id, &reason).await?);
+                    Ok(None)
                 }
+            };
+        }
+        let Some(text) = message.get("text").and_then(|t| t.as_str()) else {
+            return Ok(None);
+        };
+        match command(text) {
+            Some("skip" | "default") => match &params.default {
+                Some(default) => return Ok(Some((default.clone(), reply_id))),
+                None => self.asked_in.push(ask_again(params, reply_id, "this question has no default").await?),
+            },
+            Some("cancel") => {
+                send_reply(params, reply_id, "The build has been stopped.").await?;
+                return Err(AskAFriendError::Cancelled);
             }
+            Some("pending") => send_reply(params, reply_id, &pending_questions(params)).await?,
+            Some(_) => send_reply(params, reply_id, HELP).await?,
+            None => return self.check_answer(text, reply_id).await,
         }
+        Ok(None)
+    }
 
-        // Check if we're out of time
-        if waiting_period_start.elapsed() > params.response_timeout {
-            return Err(AskAFriendError::Timeout);
+    /// Check the answer in a reply, and ask again with the reason if it cannot be used.
+    async fn check_answer(&mut self, text: &str, reply_id: i64) -> Result<Option<(String, i64)>, AskAFriendError> {
+        let params = &self.params;
+        let answer = extract_code(text).trim();
+        let checked = check_replacement(answer, Some(params.position)).and_then(|()| {
+            if params.choices.is_empty() || params.choices.iter().any(|c| c == answer) {
+                Ok(())
+            } else {
+                Err(format!("`{answer}` is not one of the choices"))
+            }
+        });
+        match checked {
+            Ok(()) => Ok(Some((answer.to_string(), reply_id))),
+            Err(reason) => {
+                self.asked_in.push(ask_again(params, reply_id, &reason).await?);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Remind the friend of the question if it is time to, given when the waiting started.
+    async fn remind(&mut self, waiting_period_start: Instant) {
+        let (Some(reminder), Some(remind_every)) = (self.next_reminder, self.params.remind_every) else {
+            return;
+        };
+        let remaining = self.params.response_timeout.saturating_sub(waiting_period_start.elapsed());
+        if remaining <= FINAL_WARNING {
+            // This is the last one.
+            self.next_reminder = None;
+            let warning = if self.params.passed_on_to.is_some() {
+                format!("Still waiting for an answer: this will be passed on to another friend in {}s.", remaining.as_secs())
+            } else {
+                format!("Still waiting for an answer: the build will fail in {}s.", remaining.as_secs())
+            };
+            remind(&self.params, self.question_id, &warning).await;
+        } else if Instant::now() >= reminder {
+            self.next_reminder = Some(reminder + remind_every);
+            remind(&self.params, self.question_id, "Reminder: this question is still waiting for an answer.").await;
         }
     }
+
+    /// Let the friend know that their answer was used.
+    async fn confirm(&self, answer: &str, answer_id: i64) {
+        confirm_answer(&self.params, self.question_id, &self.question, answer, answer_id).await;
+    }
+
+    /// Let the friend know that the question does not need their answer anymore.
+    async fn close(&self, message: &str) {
+        best_effort("close the question", send_reply(&self.params, self.question_id, message)).await;
+    }
+}
+
+/// Download a document that a friend answered with, and get its text.
+///
+/// The error says why the document cannot be used, so that it can be shown to the friend.
+async fn document_text(params: &TelegramParams, document: &serde_json::Value) -> Result<String, String> {
+    let file_name = document.get("file_name").and_then(|n| n.as_str()).unwrap_or_default();
+    let extension = file_name.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+    if !extension.is_some_and(|extension| DOCUMENT_EXTENSIONS.contains(&extension.as_str())) {
+        return Err(format!("only .rs and .txt files can be read, not `{file_name}`"));
+    }
+    let size = document.get("file_size").and_then(|s| s.as_u64()).unwrap_or_default();
+    if size > MAX_DOCUMENT_BYTES {
+        return Err(format!("`{file_name}` is too big ({size} bytes, at most {MAX_DOCUMENT_BYTES} can be read)"));
+    }
+    let Some(file_id) = document.get("file_id").and_then(|i| i.as_str()) else {
+        return Err(format!("`{file_name}` has no file to download"));
+    };
+    let contents = download_file(&params.api(), file_id).await.map_err(|error| {
+        println!("Could not download {file_name}: {}", send_error(error));
+        format!("`{file_name}` could not be downloaded")
+    })?;
+    String::from_utf8(contents).map_err(|_| format!("`{file_name}` is not a text file"))
+}
+
+/// Reply to the question to remind the friend of it.
+async fn remind(params: &TelegramParams, question_id: i64, message: &str) {
+    best_effort("send a reminder", send_reply(params, question_id, message)).await;
 }
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::AskAFriendError;
+
+/// The official Bot API server.
+const DEFAULT_API_BASE: &str = "https://api.telegram.org";
+
+/// How many times a request is retried after being rate-limited or failing transiently.
+const MAX_RETRIES: u32 = 5;
+
+/// The first wait before retrying after a transient error; it doubles with each retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where the Bot API is, and how to get to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct Connection {
+    /// The Bot API server to use instead of the official one, like a self-hosted one.
+    pub api_base: Option<String>,
+    /// The `http://`, `https://` or `socks5://` proxy that all the requests go through.
+    pub proxy: Option<String>,
+    /// A certificate to trust besides the system's, in PEM, like the one of a company proxy.
+    pub ca_cert: Option<String>,
+}
+
+impl Connection {
+    /// Make a client that connects this way. The error says which setting is wrong.
+    pub(crate) fn client(&self) -> Result<reqwest::Client, String> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(|e| format!("invalid `proxy`: {e}"))?);
+        }
+        if let Some(ca_cert) = &self.ca_cert {
+            let certificate =
+                reqwest::Certificate::from_pem(ca_cert.as_bytes()).map_err(|e| format!("invalid `ca_cert`: {e}"))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        builder.build().map_err(|e| format!("cannot make an HTTP client: {e}"))
+    }
+}
+
+/// A bot's way to the Bot API.
+#[derive(Clone)]
+pub(crate) struct Api {
+    client: reqwest::Client,
+    base: String,
+    pub token: String,
+}
+
+impl Api {
+    pub(crate) fn new(token: &str, connection: &Connection) -> Self {
+        Api {
+            client: connection.client().expect("the connection is checked when the attributes are read"),
+            base: connection.api_base.as_deref().unwrap_or(DEFAULT_API_BASE).trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        }
+    }
+}
+
+/// An error response from the Bot API, which has `"ok": false`.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    pub error_code: u16,
+    pub description: String,
+}
+
+/// Why a call to the Bot API failed.
+#[derive(Debug)]
+pub(crate) enum CallError {
+    /// Telegram understood the request, and refused it.
+    Api(ApiError),
+    /// The request could not be made, or the response could not be read,
+    /// or we are still being rate-limited after all the retries.
+    Other(AskAFriendError),
+}
+
+impl From<AskAFriendError> for CallError {
+    fn from(error: AskAFriendError) -> Self {
+        CallError::Other(error)
+    }
+}
+
+/// Call a Bot API method with the given JSON parameters, and return the `result` of the response.
+///
+/// When Telegram answers with 429 Too Many Requests, this waits for `parameters.retry_after` seconds and tries again.
+/// Server errors (5xx) and network errors are retried with exponential backoff.
+/// Only when the retries are exhausted is the error returned.
+///
+/// A method that sends a message may have done so before the error, and retrying it would send the message twice,
+/// so those are only retried when they could not connect at all: see [`is_idempotent`].
+pub(crate) async fn call_api(api: &Api, method: &str, body: &serde_json::Value) -> Result<serde_json::Value, CallError> {
+    call_api_retrying(api, method, body, MAX_RETRIES).await
+}
+
+/// Call a Bot API method like [`call_api`], but only once, for when there is no time to wait and retry.
+pub(crate) async fn call_api_once(api: &Api, method: &str, body: &serde_json::Value) -> Result<serde_json::Value, CallError> {
+    call_api_retrying(api, method, body, 0).await
+}
+
+async fn call_api_retrying(
+    api: &Api,
+    method: &str,
+    body: &serde_json::Value,
+    max_retries: u32,
+) -> Result<serde_json::Value, CallError> {
+    let Api { client, base, token } = api;
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retries = 0;
+    loop {
+        let can_retry = retries < max_retries;
+        retries += 1;
+        // A request that did not fail to connect may have been carried out already, and is only retried if that is harmless.
+        let can_retry_any = can_retry && is_idempotent(method);
+
+        let res = client
+            .post(format!("{base}/bot{token}/{method}"))
+            .json(body)
+            .send()
+            .await;
+        let res = match res {
+            Ok(res) => res,
+            Err(error) if (can_retry_any || (can_retry && error.is_connect())) && !error.is_builder() => {
+                println!("Network error calling {method}, retrying in {backoff:?}: {}", error.without_url());
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                continue;
+            }
+            // The URL contains the bot token, which should not end up in the build log.
+            Err(error) => return Err(AskAFriendError::NetworkError(error.without_url()).into()),
+        };
+
+        let status = res.status();
+        if status.is_server_error() && can_retry_any {
+            println!("{method} returned {status}, retrying in {backoff:?}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            continue;
+        }
+
+        let json: serde_json::Value = res.json().await.map_err(|e| AskAFriendError::APIError(e.without_url()))?;
+        if json.get("ok").and_then(|ok| ok.as_bool()) == Some(true) {
+            return Ok(json.get("result").cloned().unwrap_or_default());
+        }
+
+        let error_code = json
+            .get("error_code")
+            .and_then(|c| c.as_u64())
+            .map_or(status.as_u16(), |c| c as u16);
+        let description = json
+            .get("description")
+            .and_then(|d| d.as_str())
+            .unwrap_or_default()
+            .to_string();
+        if error_code == 429 {
+            let retry_after = json
+                .get("parameters")
+                .and_then(|p| p.get("retry_after"))
+                .and_then(|r| r.as_u64())
+                .unwrap_or(1);
+            if !can_retry {
+                return Err(AskAFriendError::RateLimited { retry_after }.into());
+            }
+            println!("Rate limited calling {method}, retrying in {retry_after}s");
+            tokio::time::sleep(Duration::from_secs(retry_after)).await;
+            continue;
+        }
+
+        return Err(CallError::Api(ApiError { error_code, description }));
+    }
+}
+
+/// Whether calling the method twice does the same as calling it once. The ones that send something do not.
+fn is_idempotent(method: &str) -> bool {
+    !method.starts_with("send") && !matches!(method, "forwardMessage" | "copyMessage")
+}
+
+/// Download the file that Telegram has under the given ID, like a document that was sent to the bot.
+pub(crate) async fn download_file(api: &Api, file_id: &str) -> Result<Vec<u8>, CallError> {
+    let file = call_api(api, "getFile", &serde_json::json!({ "file_id": file_id })).await?;
+    let Some(file_path) = file.get("file_path").and_then(|p| p.as_str()) else {
+        return Err(AskAFriendError::UnknownError("file_path not found in successful response".to_string()).into());
+    };
+    // Like the method URLs, this one contains the bot token, so it is left out of the errors.
+    let Api { client, base, token } = api;
+    let res = client
+        .get(format!("{base}/file/bot{token}/{file_path}"))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| AskAFriendError::NetworkError(e.without_url()))?;
+    let bytes = res.bytes().await.map_err(|e| AskAFriendError::NetworkError(e.without_url()))?;
+    Ok(bytes.to_vec())
+}
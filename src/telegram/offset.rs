@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+
+/// The directory where what is known about the bots is kept between builds.
+pub(crate) fn state_dir() -> PathBuf {
+    let state_dir = match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(home)) => PathBuf::from(home).join(".local").join("state"),
+        (None, None) => std::env::temp_dir(),
+    };
+    state_dir.join("phone-a-friend")
+}
+
+/// The bot's ID, which is the part of the token before the colon.
+/// Files are named after it, so that the secret part of the token is not written anywhere.
+pub(crate) fn bot_id(token: &str) -> &str {
+    token.split(':').next().unwrap_or_default()
+}
+
+/// Where the `getUpdates` offset for the bot is kept between builds.
+fn offset_path(token: &str) -> PathBuf {
+    state_dir().join(format!("telegram-{}.offset", bot_id(token)))
+}
+
+/// Get the offset to start calling `getUpdates` with:
+/// one more than the last update that was consumed by an earlier question.
+pub(crate) fn load_offset(token: &str) -> i64 {
+    std::fs::read_to_string(offset_path(token))
+        .ok()
+        .and_then(|offset| offset.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Remember that all updates before `offset` have been consumed.
+///
+/// Several rustc processes may do this at the same time, so the offset never goes backwards,
+/// and the file is replaced in one step rather than written in place.
+pub(crate) fn save_offset(token: &str, offset: i64) {
+    if offset <= load_offset(token) {
+        return;
+    }
+    let path = offset_path(token);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let temporary = path.with_extension(format!("offset.{}", std::process::id()));
+    if std::fs::write(&temporary, offset.to_string()).is_ok() {
+        let _ = std::fs::rename(&temporary, &path);
+    }
+}
@@ -0,0 +1,280 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::telegram::api::{call_api_once, Api, Connection};
+use crate::telegram::offset::{bot_id, state_dir};
+use crate::telegram::best_effort;
+
+/// How long after its timeout a question may still be handled, like while waiting for edits to its answer.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long cancelling the questions may take. The build is stopping, or starting, and should not wait for it.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a question that was left open says instead, once nobody is waiting for its answer anymore.
+const CANCELLED: &str = "<i>Cancelled: the build stopped, so no answer is needed.</i>";
+
+/// A question that is waiting for an answer, as listed by `/pending`.
+///
+/// The questions are also written down in a file for each process and bot, so that the questions of a build
+/// that was killed before it could cancel them are cancelled by the next one.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct OpenQuestion {
+    /// The file is named after the bot, so the token does not need to be written down.
+    #[serde(skip)]
+    pub token: String,
+    #[serde(skip)]
+    pub connection: Connection,
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub crate_name: String,
+    pub crate_version: String,
+    pub query: String,
+    /// The question's message, in HTML, which is edited to say that it was cancelled.
+    /// For a poll, this is the message with the item that it replies to; if there is none, this is empty.
+    pub question: String,
+    /// If the question is a poll, its message, which is stopped when the question is cancelled.
+    #[serde(default)]
+    pub poll_message_id: Option<i64>,
+    /// The question is certainly not waited for anymore after this time, in seconds since the Unix epoch.
+    pub expires_at: u64,
+    /// The daemon task that asked the question, so that it can be cancelled when its build goes away.
+    #[serde(skip)]
+    pub task: Option<tokio::task::Id>,
+}
+
+/// The questions that this process is waiting for answers to.
+/// For the daemon, these are the questions of every build that uses it.
+pub(crate) static OPEN_QUESTIONS: Mutex<Vec<OpenQuestion>> = Mutex::new(Vec::new());
+
+/// Takes a question off [`OPEN_QUESTIONS`] when waiting for its answer stops, however that happens.
+pub(crate) struct OpenQuestionGuard {
+    token: String,
+    chat_id: i64,
+    message_id: i64,
+}
+
+impl Drop for OpenQuestionGuard {
+    fn drop(&mut self) {
+        let mut open = OPEN_QUESTIONS.lock().unwrap();
+        open.retain(|q| (q.chat_id, q.message_id) != (self.chat_id, self.message_id));
+        save(&self.token, &open);
+    }
+}
+
+impl OpenQuestion {
+    fn api(&self) -> Api {
+        Api::new(&self.token, &self.connection)
+    }
+}
+
+/// Put a question on [`OPEN_QUESTIONS`] until the returned guard is dropped.
+/// The question is waited for no longer than `timeout`.
+pub(crate) fn open(question: OpenQuestion, timeout: Duration) -> OpenQuestionGuard {
+    let guard = OpenQuestionGuard {
+        token: question.token.clone(),
+        chat_id: question.chat_id,
+        message_id: question.message_id,
+    };
+    let expires_at = unix_time() + (timeout + EXPIRY_MARGIN).as_secs();
+    let question = OpenQuestion { expires_at, task: tokio::task::try_id(), ..question };
+    let mut open = OPEN_QUESTIONS.lock().unwrap();
+    open.push(question);
+    save(&guard.token, &open);
+    guard
+}
+
+/// The file where this process writes down its open questions for the bot.
+fn open_path(token: &str, pid: u32) -> PathBuf {
+    state_dir().join(format!("telegram-{}.open.{pid}", bot_id(token)))
+}
+
+/// Write down this process's open questions for the bot, or remove the file if there are none.
+fn save(token: &str, open: &[OpenQuestion]) {
+    let path = open_path(token, std::process::id());
+    let questions: Vec<&OpenQuestion> = open.iter().filter(|q| q.token == token).collect();
+    if questions.is_empty() {
+        let _ = std::fs::remove_file(&path);
+        return;
+    }
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(&path, serde_json::to_string(&questions).expect("questions can always be serialized"));
+}
+
+/// Edit the questions to say that they do not need an answer anymore.
+/// The process is going away, so the requests are not retried.
+async fn cancel(api: &Api, questions: &[OpenQuestion]) {
+    let cancelling = async {
+        for question in questions {
+            if let Some(poll_message_id) = question.poll_message_id {
+                let body = serde_json::json!({ "chat_id": question.chat_id, "message_id": poll_message_id });
+                best_effort(&format!("stop poll {poll_message_id}"), call_api_once(api, "stopPoll", &body)).await;
+            }
+            // A poll without an item message cannot be edited, so it gets a reply instead.
+            let (method, body) = if question.question.is_empty() {
+                let body = serde_json::json!({
+                    "chat_id": question.chat_id,
+                    "reply_parameters": { "message_id": question.message_id },
+                    "text": CANCELLED,
+                    "parse_mode": "HTML",
+                });
+                ("sendMessage", body)
+            } else {
+                let body = serde_json::json!({
+                    "chat_id": question.chat_id,
+                    "message_id": question.message_id,
+                    "text": format!("{}\n\n{CANCELLED}", question.question),
+                    "parse_mode": "HTML",
+                });
+                ("editMessageText", body)
+            };
+            best_effort(&format!("cancel question {}", question.message_id), call_api_once(api, method, &body)).await;
+        }
+    };
+    if tokio::time::timeout(CANCEL_TIMEOUT, cancelling).await.is_err() {
+        println!("Gave up cancelling the questions after {CANCEL_TIMEOUT:?}");
+    }
+}
+
+/// Cancel the questions that the current daemon task is waiting for, because its build went away.
+// Only the daemon has tasks for builds, and the proc macro shares this module with it.
+#[allow(dead_code)]
+pub(crate) async fn cancel_task_questions() {
+    let task = tokio::task::try_id();
+    let questions: Vec<OpenQuestion> = {
+        let mut open = OPEN_QUESTIONS.lock().unwrap();
+        let (questions, rest): (Vec<_>, Vec<_>) = open.drain(..).partition(|q| q.task.is_some() && q.task == task);
+        *open = rest;
+        for question in &questions {
+            save(&question.token, &open);
+        }
+        questions
+    };
+    for question in &questions {
+        cancel(&question.api(), std::slice::from_ref(question)).await;
+    }
+}
+
+/// Cancel the open questions when the process is interrupted (with Ctrl-C) or terminated, and then exit.
+///
+/// Tokio never gives the signals back once it has handled them, so the watcher keeps running on a thread of its own
+/// for as long as the process does: otherwise the rest of the build could not be interrupted anymore.
+// The daemon cancels the questions of a build when the build goes away instead.
+#[allow(dead_code)]
+pub(crate) fn watch_for_termination() {
+    static WATCHING: Once = Once::new();
+    WATCHING.call_once(|| {
+        std::thread::spawn(|| {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            rt.block_on(async {
+                let code = terminated().await;
+                let questions = std::mem::take(&mut *OPEN_QUESTIONS.lock().unwrap());
+                let cancelling = async {
+                    for question in &questions {
+                        let _ = std::fs::remove_file(open_path(&question.token, std::process::id()));
+                    }
+                    for question in &questions {
+                        cancel(&question.api(), std::slice::from_ref(question)).await;
+                    }
+                };
+                // Asking again to stop means right now.
+                tokio::select! {
+                    _ = tokio::time::timeout(CANCEL_TIMEOUT, cancelling) => {}
+                    _ = terminated() => {}
+                }
+                std::process::exit(code);
+            });
+        });
+    });
+}
+
+/// Wait until the process is asked to stop, and return the exit code for the signal.
+pub(crate) async fn terminated() -> i32 {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return 130;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => 130,
+            _ = terminate.recv() => 143,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        130
+    }
+}
+
+/// The questions for the bot that are waiting for an answer, in this process and in the other builds that are running.
+/// So a build that polls Telegram directly lists the same questions as the daemon, which asks for all the builds using it.
+pub(crate) fn all_open(token: &str) -> Vec<OpenQuestion> {
+    let mut questions: Vec<OpenQuestion> =
+        OPEN_QUESTIONS.lock().unwrap().iter().filter(|q| q.token == token).cloned().collect();
+    let now = unix_time();
+    for (pid, path) in other_open_files(token) {
+        if is_running(pid) {
+            questions.extend(read_open(&path).into_iter().filter(|q| q.expires_at > now));
+        }
+    }
+    questions
+}
+
+/// Cancel the questions for the bot that were left open by builds that have stopped without cancelling them,
+/// like when rustc was killed.
+pub(crate) async fn clean_up_abandoned(api: &Api) {
+    for (pid, path) in other_open_files(&api.token) {
+        let questions = read_open(&path);
+        let now = unix_time();
+        if is_running(pid) && questions.iter().any(|q| q.expires_at > now) {
+            continue;
+        }
+        // Remove the file first, so that two builds starting at once do not both cancel the questions.
+        if std::fs::remove_file(&path).is_ok() {
+            println!("Cancelling {} question(s) left open by an earlier build", questions.len());
+            cancel(api, &questions).await;
+        }
+    }
+}
+
+/// The files where the other processes have written down their open questions for the bot, with their process IDs.
+fn other_open_files(token: &str) -> Vec<(u32, PathBuf)> {
+    let prefix = format!("telegram-{}.open.", bot_id(token));
+    let Ok(entries) = std::fs::read_dir(state_dir()) else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let pid = name.strip_prefix(&prefix)?.parse::<u32>().ok()?;
+            (pid != std::process::id()).then(|| (pid, entry.path()))
+        })
+        .collect()
+}
+
+/// Read the questions that a process wrote down. If the file is gone or cannot be read, there are none.
+fn read_open(path: &Path) -> Vec<OpenQuestion> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|questions| serde_json::from_str(&questions).ok())
+        .unwrap_or_default()
+}
+
+/// Whether the process is still running. Where that cannot be told, it is assumed to be,
+/// and its questions are only cleaned up once they have expired.
+fn is_running(pid: u32) -> bool {
+    !cfg!(target_os = "linux") || Path::new("/proc").join(pid.to_string()).exists()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::error::AskAFriendError;
+use crate::telegram::api::call_api;
+use crate::telegram::open_questions::{self, OpenQuestion};
+use crate::telegram::{
+    best_effort, build_thread_header, consume, message_body, question_message, send_body, send_error, send_reply,
+    TelegramParams, UpdateSource,
+};
+
+/// Telegram does not allow longer poll questions.
+const MAX_QUESTION_CHARS: usize = 300;
+
+/// How the friends vote on the `choices` in a poll, instead of replying with an answer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct PollParams {
+    /// The poll is closed as soon as this many friends have voted.
+    pub quorum: Option<usize>,
+    /// The poll is closed after this long, with however many votes it has.
+    pub deadline: Duration,
+    pub tie_break: TieBreak,
+}
+
+/// What to do when several choices got the most votes.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum TieBreak {
+    /// Use the tied choice that comes first in `choices`.
+    First,
+    /// Use one of the tied choices at random.
+    Random,
+    /// Fail the build, so that the friends can make up their minds.
+    Fail,
+}
+
+/// Ask the question as a poll with the given choices, wait until it is closed, and return the winning choice.
+///
+/// The poll is not anonymous, so that the votes of the friends who are not on `allowed_users` can be left out.
+pub(crate) async fn send_poll_and_wait(
+    params: &TelegramParams,
+    poll: &PollParams,
+    query: &str,
+    item: &str,
+    updates: &mut impl UpdateSource,
+) -> Result<String, AskAFriendError> {
+    let api = params.api();
+
+    // A poll has no room for the item, so that goes in a message of its own, and the poll replies to it.
+    let mut reply_to = build_thread_header(params).await?;
+    let mut item_message = None;
+    if !item.is_empty() {
+        let question = question_message(params, query, item);
+        let mut body = message_body(params, &question);
+        body["parse_mode"] = "HTML".into();
+        if let Some(header) = reply_to {
+            body["reply_parameters"] = serde_json::json!({ "message_id": header });
+        }
+        let message_id = send_body(params, &body).await?;
+        reply_to = Some(message_id);
+        item_message = Some((message_id, question));
+    }
+
+    let options: Vec<_> = params.choices.iter().map(|choice| serde_json::json!({ "text": choice })).collect();
+    let mut body = serde_json::json!({
+        "chat_id": params.chat_id,
+        "question": query.chars().take(MAX_QUESTION_CHARS).collect::<String>(),
+        "options": options,
+        "is_anonymous": false,
+    });
+    if let Some(message_thread_id) = params.message_thread_id {
+        body["message_thread_id"] = message_thread_id.into();
+    }
+    if let Some(reply_to) = reply_to {
+        body["reply_parameters"] = serde_json::json!({ "message_id": reply_to });
+    }
+    let result = call_api(&api, "sendPoll", &body)
+        .await
+        .map_err(send_error)?;
+    println!("{result:?}");
+    let message_id = result.get("message_id").and_then(|m| m.as_i64());
+    let poll_id = result.get("poll").and_then(|p| p.get("id")).and_then(|i| i.as_str());
+    let (Some(message_id), Some(poll_id)) = (message_id, poll_id) else {
+        return Err(AskAFriendError::UnknownError("poll not found in successful response".to_string()));
+    };
+    let (question_id, question) = item_message.unwrap_or((message_id, String::new()));
+    let _open = open_questions::open(
+        OpenQuestion {
+            token: params.token.clone(),
+            connection: params.connection.clone(),
+            chat_id: params.chat_id,
+            message_id: question_id,
+            crate_name: params.crate_name.clone(),
+            crate_version: params.crate_version.clone(),
+            query: query.to_string(),
+            question,
+            poll_message_id: Some(message_id),
+            expires_at: 0,
+            task: None,
+        },
+        poll.deadline,
+    );
+
+    // The options that each friend voted for, by user ID; a friend can change their vote until the poll is closed.
+    let mut votes: HashMap<i64, Vec<usize>> = HashMap::new();
+    let waiting_period_start = Instant::now();
+    let quorum_reached = |votes: &HashMap<i64, Vec<usize>>| poll.quorum.is_some_and(|quorum| votes.len() >= quorum);
+    loop {
+        for update in updates.next_batch(params).await? {
+            // Once the quorum is reached, the rest of the batch is left for the questions after this one.
+            if quorum_reached(&votes) {
+                break;
+            }
+            consume(updates, &update);
+            let Some(answer) = update.get("poll_answer") else {
+                continue;
+            };
+            if answer.get("poll_id").and_then(|p| p.as_str()) != Some(poll_id) || !params.is_allowed(answer.get("user")) {
+                continue;
+            }
+            let Some(user_id) = answer.get("user").and_then(|u| u.get("id")).and_then(|i| i.as_i64()) else {
+                continue;
+            };
+            let options: Vec<usize> = answer
+                .get("option_ids")
+                .and_then(|o| o.as_array())
+                .map(|o| o.iter().filter_map(|i| Some(i.as_u64()? as usize)).collect())
+                .unwrap_or_default();
+            // Retracting a vote sends an empty list.
+            if options.is_empty() {
+                votes.remove(&user_id);
+            } else {
+                votes.insert(user_id, options);
+            }
+        }
+
+        if quorum_reached(&votes) || waiting_period_start.elapsed() > poll.deadline {
+            break;
+        }
+    }
+
+    let stop = serde_json::json!({ "chat_id": params.chat_id, "message_id": message_id });
+    best_effort("close the poll", call_api(&api, "stopPoll", &stop)).await;
+
+    let winner = winner(params, poll, &votes)?;
+    send_reply(params, message_id, &format!("The vote is closed: using {winner}.")).await?;
+    Ok(winner)
+}
+
+/// Count the votes, and pick the choice with the most of them.
+fn winner(params: &TelegramParams, poll: &PollParams, votes: &HashMap<i64, Vec<usize>>) -> Result<String, AskAFriendError> {
+    if votes.is_empty() {
+        return Err(AskAFriendError::Timeout);
+    }
+    let mut counts = vec![0; params.choices.len()];
+    for option in votes.values().flatten() {
+        if let Some(count) = counts.get_mut(*option) {
+            *count += 1;
+        }
+    }
+    let most = counts.iter().copied().max().unwrap_or_default();
+    let leaders: Vec<&String> = params.choices.iter().zip(&counts).filter(|(_, &count)| count == most).map(|(c, _)| c).collect();
+    let winner = match (leaders.as_slice(), poll.tie_break) {
+        ([], _) => return Err(AskAFriendError::Timeout),
+        ([winner], _) | ([winner, ..], TieBreak::First) => winner,
+        (_, TieBreak::Random) => leaders.choose(&mut rand::thread_rng()).expect("there is always a leader"),
+        (_, TieBreak::Fail) => {
+            return Err(AskAFriendError::TiedVote(leaders.into_iter().cloned().collect()));
+        }
+    };
+    Ok(winner.to_string())
+}
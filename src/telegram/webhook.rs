@@ -0,0 +1,166 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::sync::mpsc;
+
+use crate::error::AskAFriendError;
+use crate::telegram::api::{call_api, call_api_once, Api, CallError, Connection};
+use crate::telegram::{TelegramParams, UpdateSource};
+
+/// The bots whose webhook is set, with how to reach their Bot API, so that they can be deleted when the daemon stops.
+static SET_WEBHOOKS: Mutex<Vec<(String, Connection)>> = Mutex::new(Vec::new());
+
+/// How long deleting the webhooks may take when the daemon is stopped.
+const DELETE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The header in which Telegram sends back the `secret_token` given to `setWebhook`.
+const SECRET_TOKEN_HEADER: &str = "x-telegram-bot-api-secret-token";
+
+/// Receive the updates by push: a local HTTP server that Telegram posts each update to.
+///
+/// Telegram only delivers to a public HTTPS URL, so `webhook_url` is usually a reverse proxy or tunnel
+/// that forwards to `webhook_listen` on this machine. Each request must carry the secret token that
+/// was registered with `setWebhook`, so that nobody else can inject fake answers.
+pub(crate) struct Webhook {
+    receiver: mpsc::UnboundedReceiver<serde_json::Value>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl Webhook {
+    /// Start the local receiver, then point the bot's webhook at it.
+    pub(crate) async fn start(params: &TelegramParams) -> Result<Self, AskAFriendError> {
+        let url = params.webhook_url.as_deref().unwrap_or_default();
+        let addr: SocketAddr = params.webhook_listen.parse().map_err(|e| {
+            AskAFriendError::UnknownError(format!("invalid webhook_listen address {:?}: {e}", params.webhook_listen))
+        })?;
+        let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let make_service = {
+            let secret = secret.clone();
+            make_service_fn(move |_| {
+                let secret = secret.clone();
+                let sender = sender.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        receive_update(request, secret.clone(), sender.clone())
+                    }))
+                }
+            })
+        };
+        let server = hyper::Server::try_bind(&addr)
+            .map_err(|e| AskAFriendError::UnknownError(format!("cannot listen for the webhook on {addr}: {e}")))?
+            .serve(make_service);
+        let server = tokio::spawn(async move {
+            if let Err(error) = server.await {
+                eprintln!("Webhook receiver stopped: {error}");
+            }
+        });
+
+        call_api(
+            &params.api(),
+            "setWebhook",
+            &serde_json::json!({
+                "url": url,
+                "secret_token": secret,
+            }),
+        )
+        .await
+        .map_err(|e| {
+            server.abort();
+            match e {
+                CallError::Api(error) => AskAFriendError::UnknownError(format!("setWebhook failed: {}", error.description)),
+                CallError::Other(error) => error,
+            }
+        })?;
+        SET_WEBHOOKS.lock().unwrap().push((params.token.clone(), params.connection.clone()));
+
+        Ok(Webhook { receiver, server })
+    }
+
+    /// Remove the webhook, so that the bot can be polled again, and stop the local receiver.
+    pub(crate) async fn stop(self, params: &TelegramParams) {
+        let result = call_api(&params.api(), "deleteWebhook", &serde_json::json!({})).await;
+        if let Err(error) = result {
+            println!("Could not delete the webhook: {error:?}");
+        }
+        SET_WEBHOOKS.lock().unwrap().retain(|(token, connection)| (token, connection) != (&params.token, &params.connection));
+        self.server.abort();
+    }
+}
+
+/// Delete the webhooks that are still set, when the daemon is stopped before their questions are answered.
+/// Otherwise the bots could not be polled anymore, until somebody deleted them by hand.
+pub(crate) async fn delete_all() {
+    let set = std::mem::take(&mut *SET_WEBHOOKS.lock().unwrap());
+    let deleting = async {
+        for (token, connection) in &set {
+            let result = call_api_once(&Api::new(token, connection), "deleteWebhook", &serde_json::json!({})).await;
+            if let Err(error) = result {
+                eprintln!("Could not delete the webhook: {error:?}");
+            }
+        }
+    };
+    if tokio::time::timeout(DELETE_TIMEOUT, deleting).await.is_err() {
+        eprintln!("Gave up deleting the webhooks after {DELETE_TIMEOUT:?}");
+    }
+}
+
+impl UpdateSource for Webhook {
+    async fn next_batch(&mut self, _params: &TelegramParams) -> Result<Vec<serde_json::Value>, AskAFriendError> {
+        let mut batch = vec![];
+        match tokio::time::timeout(Duration::from_secs(5), self.receiver.recv()).await {
+            Ok(Some(update)) => batch.push(update),
+            Ok(None) => {
+                return Err(AskAFriendError::UnknownError("the webhook receiver stopped".to_string()));
+            }
+            Err(_) => return Ok(batch),
+        }
+        while let Ok(update) = self.receiver.try_recv() {
+            batch.push(update);
+        }
+        Ok(batch)
+    }
+
+    fn consumed(&mut self, _update_id: i64) {
+        // Telegram considers a pushed update delivered as soon as the receiver accepts it.
+    }
+}
+
+/// Handle one request to the webhook receiver.
+async fn receive_update(
+    request: Request<Body>,
+    secret: String,
+    sender: mpsc::UnboundedSender<serde_json::Value>,
+) -> Result<Response<Body>, Infallible> {
+    let authorized = request
+        .headers()
+        .get(SECRET_TOKEN_HEADER)
+        .is_some_and(|token| token.as_bytes() == secret.as_bytes());
+    if !authorized {
+        return Ok(status_response(StatusCode::UNAUTHORIZED));
+    }
+
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+    match serde_json::from_slice(&body) {
+        Ok(update) => {
+            let _ = sender.send(update);
+            Ok(status_response(StatusCode::OK))
+        }
+        Err(_) => Ok(status_response(StatusCode::BAD_REQUEST)),
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}